@@ -0,0 +1,430 @@
+use crate::{error::ShellError, parse::CommandHandler};
+
+// variables holding expressions are evaluated recursively, up to this depth
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Op(&'static str),
+}
+
+// longest operators first so that e.g. `<<=` is not read as `<<` and `=`
+const OPERATORS: [&str; 39] = [
+    "<<=", ">>=", "**", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "+=", "-=",
+    "*=", "/=", "%=", "&=", "^=", "|=", "+", "-", "*", "/", "%", "<", ">", "&", "^", "|", "!", "~",
+    "?", ":", "=", "(", ")", ",",
+];
+
+#[derive(Debug)]
+enum Expr {
+    Number(i64),
+    Variable(String),
+    Unary(&'static str, Box<Expr>),
+    /// `++x` and `--x`
+    PreIncrement(String, i64),
+    /// `x++` and `x--`
+    PostIncrement(String, i64),
+    Binary(&'static str, Box<Expr>, Box<Expr>),
+    /// `x = e`, or `x op= e` with the operator
+    Assign(String, Option<&'static str>, Box<Expr>),
+    Ternary(Box<Expr>, Box<Expr>, Box<Expr>),
+}
+
+/// Binding power of each binary operator, from `,` (loosest) to `**` (tightest).
+fn precedence(op: &str) -> Option<u8> {
+    let power = match op {
+        "," => 1,
+        "||" => 4,
+        "&&" => 5,
+        "|" => 6,
+        "^" => 7,
+        "&" => 8,
+        "==" | "!=" => 9,
+        "<" | "<=" | ">" | ">=" => 10,
+        "<<" | ">>" => 11,
+        "+" | "-" => 12,
+        "*" | "/" | "%" => 13,
+        "**" => 14,
+        _ => return None,
+    };
+    Some(power)
+}
+
+const ASSIGN_POWER: u8 = 2;
+const TERNARY_POWER: u8 = 3;
+const UNARY_POWER: u8 = 15;
+
+struct Parser<'a> {
+    text: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Result<Self, ShellError> {
+        let mut tokens = Vec::new();
+        let chars: Vec<char> = text.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let ch = chars[i];
+            if ch.is_whitespace() {
+                i += 1;
+            } else if ch.is_ascii_digit() {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_ascii_alphanumeric() || matches!(chars[i], '#' | '@' | '_'))
+                {
+                    i += 1;
+                }
+                let literal: String = chars[start..i].iter().collect();
+                tokens.push(Token::Number(Self::number(text, &literal)?));
+            } else if ch == '_' || ch.is_ascii_alphabetic() {
+                let start = i;
+                while i < chars.len() && (chars[i] == '_' || chars[i].is_ascii_alphanumeric()) {
+                    i += 1;
+                }
+                tokens.push(Token::Name(chars[start..i].iter().collect()));
+            } else {
+                let rest: String = chars[i..].iter().take(3).collect();
+                let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(**op)) else {
+                    return Err(syntax_error(text, "invalid arithmetic operator"));
+                };
+                tokens.push(Token::Op(op));
+                i += op.len();
+            }
+        }
+        Ok(Parser {
+            text,
+            tokens,
+            pos: 0,
+        })
+    }
+
+    /// Reads a constant: decimal, octal with a leading `0`, hex with `0x`, or `base#digits`
+    /// for any base from 2 to 64.
+    fn number(text: &str, literal: &str) -> Result<i64, ShellError> {
+        let (base, digits) = match literal.split_once('#') {
+            Some((base, digits)) => match base.parse::<u32>() {
+                Ok(base) if (2..=64).contains(&base) => (base, digits),
+                _ => return Err(syntax_error(text, "invalid arithmetic base")),
+            },
+            None => match literal.strip_prefix("0x").or(literal.strip_prefix("0X")) {
+                Some(digits) => (16, digits),
+                None if literal.len() > 1 && literal.starts_with('0') => (8, &literal[1..]),
+                None => (10, literal),
+            },
+        };
+        if digits.is_empty() {
+            return Err(syntax_error(text, "invalid number"));
+        }
+        let mut value: i64 = 0;
+        for ch in digits.chars() {
+            let digit = match ch {
+                '0'..='9' => ch as u32 - '0' as u32,
+                'a'..='z' => ch as u32 - 'a' as u32 + 10,
+                'A'..='Z' if base <= 36 => ch as u32 - 'A' as u32 + 10,
+                'A'..='Z' => ch as u32 - 'A' as u32 + 36,
+                '@' => 62,
+                '_' => 63,
+                _ => u32::MAX,
+            };
+            if digit >= base {
+                return Err(syntax_error(text, "value too great for base"));
+            }
+            value = value.wrapping_mul(base as i64).wrapping_add(digit as i64);
+        }
+        Ok(value)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, op: &str) -> Result<(), ShellError> {
+        match self.next() {
+            Some(Token::Op(found)) if found == op => Ok(()),
+            _ => Err(syntax_error(self.text, &format!("`{}' expected", op))),
+        }
+    }
+
+    fn parse(mut self) -> Result<Option<Expr>, ShellError> {
+        if self.tokens.is_empty() {
+            return Ok(None);
+        }
+        let expr = self.expression(0)?;
+        match self.peek() {
+            None => Ok(Some(expr)),
+            Some(_) => Err(syntax_error(self.text, "syntax error in expression")),
+        }
+    }
+
+    fn expression(&mut self, min_power: u8) -> Result<Expr, ShellError> {
+        let mut left = self.unary()?;
+        while let Some(Token::Op(op)) = self.peek().cloned() {
+            if op == "?" {
+                if TERNARY_POWER < min_power {
+                    break;
+                }
+                self.next();
+                let then = self.expression(0)?;
+                self.expect(":")?;
+                let otherwise = self.expression(TERNARY_POWER)?;
+                left = Expr::Ternary(Box::new(left), Box::new(then), Box::new(otherwise));
+                continue;
+            }
+            if op.ends_with('=') && !matches!(op, "==" | "!=" | "<=" | ">=") {
+                if ASSIGN_POWER < min_power {
+                    break;
+                }
+                let Expr::Variable(name) = left else {
+                    return Err(syntax_error(
+                        self.text,
+                        "attempted assignment to non-variable",
+                    ));
+                };
+                self.next();
+                let value = self.expression(ASSIGN_POWER)?;
+                let op = op.strip_suffix('=').filter(|op| !op.is_empty());
+                let op = op.and_then(|op| OPERATORS.iter().copied().find(|known| *known == op));
+                left = Expr::Assign(name, op, Box::new(value));
+                continue;
+            }
+            let Some(power) = precedence(op) else {
+                break;
+            };
+            if power < min_power {
+                break;
+            }
+            self.next();
+            // `**` groups to the right, everything else to the left
+            let next_power = if op == "**" { power } else { power + 1 };
+            let right = self.expression(next_power)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ShellError> {
+        match self.next() {
+            Some(Token::Number(value)) => Ok(Expr::Number(value)),
+            Some(Token::Name(name)) => match self.peek() {
+                Some(Token::Op(op @ ("++" | "--"))) => {
+                    let step = if *op == "++" { 1 } else { -1 };
+                    self.next();
+                    Ok(Expr::PostIncrement(name, step))
+                }
+                _ => Ok(Expr::Variable(name)),
+            },
+            Some(Token::Op(op @ ("++" | "--"))) => match self.next() {
+                Some(Token::Name(name)) => {
+                    Ok(Expr::PreIncrement(name, if op == "++" { 1 } else { -1 }))
+                }
+                _ => Err(syntax_error(self.text, "operand expected")),
+            },
+            Some(Token::Op(op @ ("+" | "-" | "!" | "~"))) => {
+                let operand = self.expression(UNARY_POWER)?;
+                Ok(Expr::Unary(op, Box::new(operand)))
+            }
+            Some(Token::Op("(")) => {
+                let expr = self.expression(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            _ => Err(syntax_error(self.text, "operand expected")),
+        }
+    }
+}
+
+fn syntax_error(text: &str, message: &str) -> ShellError {
+    ShellError::ArithmeticError {
+        expr: text.trim().to_string(),
+        message: message.to_string(),
+    }
+}
+
+struct Evaluator<'a> {
+    handler: &'a mut CommandHandler,
+    text: &'a str,
+    depth: usize,
+}
+
+impl Evaluator<'_> {
+    fn variable(&mut self, name: &str) -> Result<i64, ShellError> {
+        let value = self.handler.variables.get(name).unwrap_or_default();
+        let value = value.trim();
+        if value.is_empty() {
+            return Ok(0);
+        }
+        if let Ok(value) = value.parse() {
+            return Ok(value);
+        }
+        if self.depth >= MAX_DEPTH {
+            return Err(syntax_error(value, "expression recursion level exceeded"));
+        }
+        let expr = Parser::new(value)?.parse()?;
+        let mut evaluator = Evaluator {
+            handler: self.handler,
+            text: value,
+            depth: self.depth + 1,
+        };
+        expr.map_or(Ok(0), |expr| evaluator.eval(&expr))
+    }
+
+    fn assign(&mut self, name: &str, value: i64) -> i64 {
+        self.handler.set_var(name, &value.to_string());
+        value
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<i64, ShellError> {
+        match expr {
+            Expr::Number(value) => Ok(*value),
+            Expr::Variable(name) => self.variable(name),
+            Expr::Unary(op, operand) => {
+                let value = self.eval(operand)?;
+                Ok(match *op {
+                    "-" => value.wrapping_neg(),
+                    "!" => (value == 0) as i64,
+                    "~" => !value,
+                    _ => value,
+                })
+            }
+            Expr::PreIncrement(name, step) => {
+                let value = self.variable(name)?.wrapping_add(*step);
+                Ok(self.assign(name, value))
+            }
+            Expr::PostIncrement(name, step) => {
+                let value = self.variable(name)?;
+                self.assign(name, value.wrapping_add(*step));
+                Ok(value)
+            }
+            Expr::Binary("&&", left, right) => {
+                Ok((self.eval(left)? != 0 && self.eval(right)? != 0) as i64)
+            }
+            Expr::Binary("||", left, right) => {
+                Ok((self.eval(left)? != 0 || self.eval(right)? != 0) as i64)
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                self.binary(op, left, right)
+            }
+            Expr::Assign(name, op, value) => {
+                let mut value = self.eval(value)?;
+                if let Some(op) = op {
+                    let current = self.variable(name)?;
+                    value = self.binary(op, current, value)?;
+                }
+                Ok(self.assign(name, value))
+            }
+            Expr::Ternary(condition, then, otherwise) => match self.eval(condition)? {
+                0 => self.eval(otherwise),
+                _ => self.eval(then),
+            },
+        }
+    }
+
+    fn binary(&self, op: &str, left: i64, right: i64) -> Result<i64, ShellError> {
+        let value = match op {
+            "," => right,
+            "|" => left | right,
+            "^" => left ^ right,
+            "&" => left & right,
+            "==" => (left == right) as i64,
+            "!=" => (left != right) as i64,
+            "<" => (left < right) as i64,
+            "<=" => (left <= right) as i64,
+            ">" => (left > right) as i64,
+            ">=" => (left >= right) as i64,
+            "<<" => left.wrapping_shl(right as u32),
+            ">>" => left.wrapping_shr(right as u32),
+            "+" => left.wrapping_add(right),
+            "-" => left.wrapping_sub(right),
+            "*" => left.wrapping_mul(right),
+            "/" | "%" if right == 0 => {
+                return Err(ShellError::DivisionByZero(self.text.trim().to_string()));
+            }
+            "/" => left.wrapping_div(right),
+            "%" => left.wrapping_rem(right),
+            "**" if right < 0 => return Err(syntax_error(self.text, "exponent less than 0")),
+            "**" => left.wrapping_pow(right.min(u32::MAX as i64) as u32),
+            _ => return Err(syntax_error(self.text, "syntax error in expression")),
+        };
+        Ok(value)
+    }
+}
+
+/// Evaluates an arithmetic expression whose parameters and substitutions have already been
+/// expanded; an empty expression is 0.
+pub fn evaluate(handler: &mut CommandHandler, text: &str) -> Result<i64, ShellError> {
+    let Some(expr) = Parser::new(text)?.parse()? else {
+        return Ok(0);
+    };
+    Evaluator {
+        handler,
+        text,
+        depth: 0,
+    }
+    .eval(&expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(handler: &mut CommandHandler, text: &str) -> i64 {
+        evaluate(handler, text).unwrap()
+    }
+
+    #[test]
+    fn follows_c_precedence() {
+        let mut handler = CommandHandler::new();
+        assert_eq!(eval(&mut handler, "2 + 3 * 4"), 14);
+        assert_eq!(eval(&mut handler, "(2 + 3) * 4"), 20);
+        assert_eq!(eval(&mut handler, "2 ** 3 ** 2"), 512);
+        assert_eq!(eval(&mut handler, "1 < 2 && 3 > 4 || 5"), 1);
+        assert_eq!(eval(&mut handler, "-7 / 2"), -3);
+        assert_eq!(eval(&mut handler, "1 ? 2 : 3"), 2);
+    }
+
+    #[test]
+    fn reads_numbers_in_other_bases() {
+        let mut handler = CommandHandler::new();
+        assert_eq!(eval(&mut handler, "16#ff"), 255);
+        assert_eq!(eval(&mut handler, "2#101"), 5);
+        assert_eq!(eval(&mut handler, "0x10"), 16);
+        assert_eq!(eval(&mut handler, "010"), 8);
+    }
+
+    #[test]
+    fn assigns_and_increments_variables() {
+        let mut handler = CommandHandler::new();
+        assert_eq!(eval(&mut handler, "x = 5"), 5);
+        assert_eq!(eval(&mut handler, "x += 2"), 7);
+        assert_eq!(eval(&mut handler, "x++"), 7);
+        assert_eq!(eval(&mut handler, "--x"), 7);
+        assert_eq!(handler.variables.get("x").as_deref(), Some("7"));
+        // an unset variable counts as 0
+        assert_eq!(eval(&mut handler, "unset_variable + 1"), 1);
+    }
+
+    #[test]
+    fn reports_errors() {
+        let mut handler = CommandHandler::new();
+        assert!(matches!(
+            evaluate(&mut handler, "1 / 0"),
+            Err(ShellError::DivisionByZero(_))
+        ));
+        assert!(matches!(
+            evaluate(&mut handler, "1 +"),
+            Err(ShellError::ArithmeticError { .. })
+        ));
+    }
+}
@@ -1,8 +1,13 @@
 #![allow(unused_variables)]
+use crate::arith;
 use crate::error::ShellError;
-use crate::parse::{self, Arg, BuiltIn, ParsedCommand, RunTimeEnvPath, ShellResult};
+use crate::glob::GlobOptions;
+use crate::parse::{
+    BuiltIn, CommandHandler, LoopControl, ParsedCommand, RunTimeEnvPath, ShellResult,
+};
 use crate::shellio::IOHandler;
 use crate::utils::*;
+use crate::variable::Variables;
 use std::env;
 use std::path::Path;
 pub fn not_found(parsed_command: ParsedCommand, paths: RunTimeEnvPath, io_handler: &IOHandler) {
@@ -14,68 +19,387 @@ pub fn not_found(parsed_command: ParsedCommand, paths: RunTimeEnvPath, io_handle
 
 pub fn echo(
     parsed_command: ParsedCommand,
-    paths: RunTimeEnvPath,
+    command_handler: &mut CommandHandler,
     io_handler: &IOHandler,
 ) -> ShellResult {
     io_handler.stdout(format_args!("{}", parsed_command.args.join(" ")));
-    return Ok(1);
+    Ok(0)
 }
 
+/// `exit [n]`: leaves the shell with status `n`, by default that of the last command.
 pub fn _exit(
     parsed_command: ParsedCommand,
-    paths: RunTimeEnvPath,
+    command_handler: &mut CommandHandler,
     io_handler: &IOHandler,
 ) -> ShellResult {
-    std::process::exit(0)
+    let code = match parsed_command.args.first() {
+        None => command_handler.variables.last_status,
+        Some(arg) => match arg.parse::<i64>() {
+            Ok(code) => (code & 0xff) as i32,
+            Err(_) => {
+                io_handler.stderr(format_args!(
+                    "{}",
+                    ShellError::NumericArgumentRequired {
+                        cmd: BuiltIn::EXIT,
+                        arg: arg.clone(),
+                    }
+                ));
+                2
+            }
+        },
+    };
+    std::process::exit(code)
 }
 
+/// `type name...`: the status is 1 if any name is not found.
 pub fn _type(
     parsed_command: ParsedCommand,
-    paths: RunTimeEnvPath,
+    command_handler: &mut CommandHandler,
     io_handler: &IOHandler,
 ) -> ShellResult {
-    let command = parsed_command.args.join(" ");
-    match command.parse::<BuiltIn>() {
-        Ok(cmd) => {
+    let mut status = 0;
+    for command in &parsed_command.args {
+        if let Some(function) = command_handler.functions.get(command) {
+            io_handler.stdout(format_args!(
+                "{} is a function\n{}",
+                command, function.source
+            ));
+        } else if let Ok(cmd) = command.parse::<BuiltIn>() {
             io_handler.stdout(format_args!("{} is a shell builtin", cmd));
-            Ok(1)
+        } else if let Some(path) = search_file_in_paths(command, command_handler.get_runtime_path())
+        {
+            io_handler.stdout(format_args!("{} is {}", command, path.display()));
+        } else {
+            io_handler.stderr(format_args!(
+                "{}",
+                ShellError::CommandNotFound(command.clone())
+            ));
+            status = 1;
         }
-        _ => match search_file_in_paths(&command, paths) {
-            Some(path) => {
-                io_handler.stdout(format_args!("{} is {}", command, path.display()));
-                Ok(1)
-            }
-            None => Err(ShellError::CommandNotFound(command)),
-        },
     }
+    Ok(status)
 }
 
 pub fn pwd(
     parsed_command: ParsedCommand,
-    paths: RunTimeEnvPath,
+    command_handler: &mut CommandHandler,
     io_handler: &IOHandler,
 ) -> ShellResult {
     let work_dir = env::current_dir().expect("");
     io_handler.stdout(format_args!("{}", work_dir.display()));
-    return Ok(1);
+    Ok(0)
 }
 
+/// `cd [dir]`: with no argument goes to `$HOME`, and `cd -` to `$OLDPWD`. `PWD` and
+/// `OLDPWD` are updated on success.
 pub fn cd(
     parsed_command: ParsedCommand,
-    paths: RunTimeEnvPath,
+    command_handler: &mut CommandHandler,
     io_handler: &IOHandler,
 ) -> ShellResult {
-    let mut target_dir: &Arg = &parsed_command.args[0];
-    let home = parse::get_env_home();
-    if target_dir == "~" {
-        target_dir = &home;
-    }
-    let path = Path::new(&target_dir);
-    match env::set_current_dir(path) {
-        Ok(_) => Ok(1),
-        Err(_) => Err(ShellError::DirectoryNotExist {
+    let not_set = |name: &str| ShellError::VariableNotSet {
+        cmd: BuiltIn::CD,
+        name: name.to_string(),
+    };
+    if parsed_command.args.len() > 1 {
+        return Err(ShellError::TooManyArguments(BuiltIn::CD));
+    }
+    let variables = &command_handler.variables;
+    let target_dir = match parsed_command.args.first().map(String::as_str) {
+        None => variables.get("HOME").ok_or_else(|| not_set("HOME"))?,
+        Some("-") => variables.get("OLDPWD").ok_or_else(|| not_set("OLDPWD"))?,
+        Some(dir) => dir.to_string(),
+    };
+    let old_dir = match variables.get("PWD") {
+        Some(pwd) => pwd,
+        None => env::current_dir()?.display().to_string(),
+    };
+    if env::set_current_dir(Path::new(&target_dir)).is_err() {
+        return Err(ShellError::DirectoryNotExist {
             cmd: BuiltIn::CD,
-            dir: target_dir.to_string(),
-        }),
+            dir: target_dir,
+        });
+    }
+    let new_dir = env::current_dir()?.display().to_string();
+    if parsed_command.args.first().is_some_and(|arg| arg == "-") {
+        io_handler.stdout(format_args!("{}", new_dir));
+    }
+    for (name, value) in [("OLDPWD", old_dir), ("PWD", new_dir)] {
+        command_handler.set_var(name, &value);
+        command_handler.variables.export(name);
+    }
+    Ok(0)
+}
+
+pub fn export(
+    parsed_command: ParsedCommand,
+    command_handler: &mut CommandHandler,
+    io_handler: &IOHandler,
+) -> ShellResult {
+    if parsed_command.args.is_empty() || parsed_command.args == ["-p"] {
+        for (name, value) in command_handler.variables.environment() {
+            let value: String = value
+                .chars()
+                .flat_map(|ch| match ch {
+                    '"' | '\\' | '$' | '`' => vec!['\\', ch],
+                    _ => vec![ch],
+                })
+                .collect();
+            io_handler.stdout(format_args!("declare -x {}=\"{}\"", name, value));
+        }
+        return Ok(0);
+    }
+    let mut status = 0;
+    for arg in &parsed_command.args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !Variables::is_name(name) {
+            io_handler.stderr(format_args!(
+                "{}",
+                ShellError::InvalidIdentifier {
+                    cmd: BuiltIn::EXPORT,
+                    name: arg.clone(),
+                }
+            ));
+            status = 1;
+            continue;
+        }
+        if let Some(value) = value {
+            command_handler.set_var(name, value);
+        }
+        command_handler.variables.export(name);
+    }
+    Ok(status)
+}
+
+/// `unset [-v] name...` removes variables, and `unset -f name...` functions.
+pub fn unset(
+    parsed_command: ParsedCommand,
+    command_handler: &mut CommandHandler,
+    io_handler: &IOHandler,
+) -> ShellResult {
+    let mut status = 0;
+    let functions = parsed_command.args.iter().any(|arg| arg == "-f");
+    for name in parsed_command
+        .args
+        .iter()
+        .filter(|arg| *arg != "-v" && *arg != "-f")
+    {
+        if functions {
+            command_handler.functions.remove(name);
+            continue;
+        }
+        if !Variables::is_name(name) {
+            io_handler.stderr(format_args!(
+                "{}",
+                ShellError::InvalidIdentifier {
+                    cmd: BuiltIn::UNSET,
+                    name: name.clone(),
+                }
+            ));
+            status = 1;
+            continue;
+        }
+        command_handler.unset_var(name);
+    }
+    Ok(status)
+}
+
+/// `let expression...`: the status is 0 when the last expression is non-zero.
+pub fn _let(
+    parsed_command: ParsedCommand,
+    command_handler: &mut CommandHandler,
+    io_handler: &IOHandler,
+) -> ShellResult {
+    if parsed_command.args.is_empty() {
+        return Err(ShellError::ArithmeticError {
+            expr: BuiltIn::LET.to_string(),
+            message: "expression expected".to_string(),
+        });
+    }
+    let mut value = 0;
+    for arg in &parsed_command.args {
+        value = arith::evaluate(command_handler, arg)?;
+    }
+    Ok((value == 0) as i32)
+}
+
+/// `break [n]`: leaves the `n`th enclosing loop.
+pub fn _break(
+    parsed_command: ParsedCommand,
+    command_handler: &mut CommandHandler,
+    io_handler: &IOHandler,
+) -> ShellResult {
+    jump(
+        BuiltIn::BREAK,
+        LoopControl::Break,
+        parsed_command,
+        command_handler,
+        io_handler,
+    )
+}
+
+/// `continue [n]`: starts the next iteration of the `n`th enclosing loop.
+pub fn _continue(
+    parsed_command: ParsedCommand,
+    command_handler: &mut CommandHandler,
+    io_handler: &IOHandler,
+) -> ShellResult {
+    jump(
+        BuiltIn::CONTINUE,
+        LoopControl::Continue,
+        parsed_command,
+        command_handler,
+        io_handler,
+    )
+}
+
+fn jump(
+    cmd: BuiltIn,
+    control: fn(usize) -> LoopControl,
+    parsed_command: ParsedCommand,
+    command_handler: &mut CommandHandler,
+    io_handler: &IOHandler,
+) -> ShellResult {
+    let count = match parsed_command.args.first() {
+        None => 1,
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                return Err(ShellError::LoopCountOutOfRange {
+                    cmd,
+                    arg: arg.clone(),
+                });
+            }
+        },
+    };
+    if command_handler.loop_depth == 0 {
+        io_handler.stderr(format_args!("{}", ShellError::NotInLoop(cmd)));
+        return Ok(0);
+    }
+    // `break 5` inside two loops leaves both
+    command_handler.loop_control = Some(control(count.min(command_handler.loop_depth)));
+    Ok(0)
+}
+
+/// `local name[=value]...`: makes variables local to the running function; one given no
+/// value starts out unset.
+pub fn local(
+    parsed_command: ParsedCommand,
+    command_handler: &mut CommandHandler,
+    io_handler: &IOHandler,
+) -> ShellResult {
+    if !command_handler.variables.in_function() {
+        return Err(ShellError::NotInFunction(BuiltIn::LOCAL));
+    }
+    let mut status = 0;
+    for arg in &parsed_command.args {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        if !Variables::is_name(name) {
+            io_handler.stderr(format_args!(
+                "{}",
+                ShellError::InvalidIdentifier {
+                    cmd: BuiltIn::LOCAL,
+                    name: arg.clone(),
+                }
+            ));
+            status = 1;
+            continue;
+        }
+        command_handler.variables.make_local(name);
+        match value {
+            Some(value) => command_handler.set_var(name, value),
+            None => command_handler.unset_var(name),
+        }
+    }
+    Ok(status)
+}
+
+/// `return [n]`: leaves the running function with status `n`, by default that of the last
+/// command.
+pub fn _return(
+    parsed_command: ParsedCommand,
+    command_handler: &mut CommandHandler,
+    io_handler: &IOHandler,
+) -> ShellResult {
+    if !command_handler.variables.in_function() {
+        return Err(ShellError::NotInFunction(BuiltIn::RETURN));
+    }
+    let status = match parsed_command.args.first() {
+        None => command_handler.variables.last_status,
+        Some(arg) => match arg.parse::<i64>() {
+            Ok(status) => (status & 0xff) as i32,
+            Err(_) => {
+                io_handler.stderr(format_args!(
+                    "{}",
+                    ShellError::NumericArgumentRequired {
+                        cmd: BuiltIn::RETURN,
+                        arg: arg.clone(),
+                    }
+                ));
+                2
+            }
+        },
+    };
+    command_handler.loop_control = Some(LoopControl::Return(status));
+    Ok(status)
+}
+
+/// `shopt [-pqsu] [name...]`: shows or toggles the pathname expansion options.
+pub fn shopt(
+    parsed_command: ParsedCommand,
+    command_handler: &mut CommandHandler,
+    io_handler: &IOHandler,
+) -> ShellResult {
+    let mut flags = String::new();
+    let mut names: Vec<&str> = Vec::new();
+    for arg in &parsed_command.args {
+        match arg.strip_prefix('-') {
+            Some(flag) if names.is_empty() && !flag.is_empty() => flags.push_str(flag),
+            _ => names.push(arg),
+        }
+    }
+    if names.is_empty() {
+        names = GlobOptions::NAMES.to_vec();
+        if flags.contains('s') || flags.contains('u') {
+            // `shopt -s` alone lists the options that are on
+            let on = flags.contains('s');
+            let options = &command_handler.glob_options;
+            names.retain(|name| options.get(name) == Some(on));
+            flags = flags.replace(['s', 'u'], "");
+        }
+    }
+    let mut status = 0;
+    for name in names {
+        let Some(value) = command_handler.glob_options.get_mut(name) else {
+            io_handler.stderr(format_args!(
+                "{}",
+                ShellError::InvalidOption {
+                    cmd: BuiltIn::SHOPT,
+                    name: name.to_string(),
+                }
+            ));
+            status = 1;
+            continue;
+        };
+        if flags.contains('s') {
+            *value = true;
+        } else if flags.contains('u') {
+            *value = false;
+        } else if flags.contains('q') {
+            status = status.max(!*value as i32);
+        } else if flags.contains('p') {
+            let flag = if *value { 's' } else { 'u' };
+            io_handler.stdout(format_args!("shopt -{} {}", flag, name));
+        } else {
+            let state = if *value { "on" } else { "off" };
+            io_handler.stdout(format_args!("{:<15}\t{}", name, state));
+        }
     }
+    Ok(status)
 }
@@ -1,4 +1,4 @@
-use std::io;
+use std::{fmt, io, ops::Range};
 use thiserror::Error;
 
 use crate::parse::BuiltIn;
@@ -28,9 +28,138 @@ pub enum ShellError {
     #[error("{cmd}: {dir}: No such file or directory")]
     DirectoryNotExist { cmd: BuiltIn, dir: String },
 
-    #[error("Invalid syntax!")]
-    InvalidSyntax,
+    #[error("{cmd}: {arg}: loop count out of range")]
+    LoopCountOutOfRange { cmd: BuiltIn, arg: String },
+
+    #[error("{0}: only meaningful in a `for', `while', or `until' loop")]
+    NotInLoop(BuiltIn),
+
+    #[error("{0}: can only be used in a function")]
+    NotInFunction(BuiltIn),
+
+    #[error("{cmd}: {arg}: numeric argument required")]
+    NumericArgumentRequired { cmd: BuiltIn, arg: String },
+
+    #[error("{0}: too many arguments")]
+    TooManyArguments(BuiltIn),
+
+    #[error("{cmd}: {name} not set")]
+    VariableNotSet { cmd: BuiltIn, name: String },
+
+    #[error("{0}: No such file or directory")]
+    FileNotExist(String),
+
+    #[error("{0}: Bad file descriptor")]
+    BadFileDescriptor(i32),
+
+    #[error("{cmd}: `{name}': not a valid identifier")]
+    InvalidIdentifier { cmd: BuiltIn, name: String },
+
+    #[error("{0}: bad substitution")]
+    BadSubstitution(String),
+
+    #[error("{name}: {message}")]
+    ParameterNotSet { name: String, message: String },
+
+    #[error("{expr}: {message}")]
+    ArithmeticError { expr: String, message: String },
+
+    #[error("{0}: division by 0")]
+    DivisionByZero(String),
+
+    #[error("no match: {0}")]
+    NoMatch(String),
+
+    #[error("{cmd}: {name}: invalid shell option name")]
+    InvalidOption { cmd: BuiltIn, name: String },
+
+    #[error("{0}: ambiguous redirect")]
+    AmbiguousRedirect(String),
+
+    #[error("{0}")]
+    Syntax(Diagnostic),
 
     #[error("")]
     NullInput,
 }
+
+impl ShellError {
+    /// The exit status `$?` reports when a command fails with this error.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ShellError::CommandNotFound(_) => 127,
+            ShellError::ProcessStartError { source, .. } => match source.kind() {
+                io::ErrorKind::NotFound => 127,
+                _ => 126,
+            },
+            ShellError::ProcessExitError { code, .. } => *code,
+            ShellError::Syntax(_) => 2,
+            ShellError::NullInput => 0,
+            _ => 1,
+        }
+    }
+}
+
+/// A syntax error pointing at the offending text of the input it was found in. Displays as
+/// the message followed by the input line with a caret under the span.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub message: String,
+    /// Byte offsets of the offending text in `source`.
+    pub span: Range<usize>,
+    pub source: String,
+    /// The script being run, if the input did not come from the prompt.
+    pub file: Option<String>,
+    /// Whether the input merely ended too early, so that more lines could complete it.
+    pub incomplete: bool,
+}
+
+impl Diagnostic {
+    pub fn new(message: String, span: Range<usize>, source: &str) -> Diagnostic {
+        Diagnostic {
+            message,
+            span,
+            source: source.to_string(),
+            file: None,
+            incomplete: false,
+        }
+    }
+
+    /// Names the script the source was read from.
+    pub fn in_file(mut self, file: &str) -> Diagnostic {
+        self.file = Some(file.to_string());
+        self
+    }
+}
+
+/// The 1-based line and column of byte `offset` in `source`.
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    (line, before[line_start..].chars().count() + 1)
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let start = self.span.start.min(self.source.len());
+        let (line, _) = line_column(&self.source, start);
+        let line_start = self.source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = self.source[start..]
+            .find('\n')
+            .map_or(self.source.len(), |i| start + i);
+        if let Some(file) = &self.file {
+            write!(f, "{}: line {}: ", file, line)?;
+        }
+        writeln!(f, "syntax error: {}", self.message)?;
+        writeln!(f, "{}", &self.source[line_start..line_end])?;
+        // keep tabs so the caret lines up with the text above it
+        let indent: String = self.source[line_start..start]
+            .chars()
+            .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+            .collect();
+        let end = self.span.end.clamp(start, line_end);
+        let width = self.source[start..end].chars().count().max(1);
+        write!(f, "{}{}", indent, "^".repeat(width))
+    }
+}
@@ -0,0 +1,1113 @@
+use std::{iter::Peekable, str::Chars};
+
+use crate::{
+    arith, error::ShellError, glob, lexer, parse::CommandHandler, pattern::Pattern, utils,
+    variable::Variables,
+};
+
+const IFS_DEFAULT: &str = " \t\n";
+
+/// A partially expanded field; each character remembers whether it was quoted, since only
+/// unquoted characters take part in field splitting and pathname expansion.
+type Field = Vec<(char, bool)>;
+
+/// Accumulates the fields produced by expanding one word.
+#[derive(Default)]
+struct Fields {
+    done: Vec<Field>,
+    current: Field,
+    // the current field exists even while empty, e.g. after `""`
+    started: bool,
+    // the last field was ended by IFS whitespace, which absorbs one following
+    // non-whitespace IFS character
+    after_whitespace: bool,
+}
+
+impl Fields {
+    fn push(&mut self, ch: char, quoted: bool) {
+        self.current.push((ch, quoted));
+        self.started = true;
+        self.after_whitespace = false;
+    }
+
+    fn push_str(&mut self, text: &str, quoted: bool) {
+        for ch in text.chars() {
+            self.push(ch, quoted);
+        }
+    }
+
+    fn delimit(&mut self) {
+        if self.started {
+            self.done.push(std::mem::take(&mut self.current));
+            self.started = false;
+        }
+    }
+
+    /// Splits the result of an unquoted expansion on the characters of `ifs`. IFS whitespace
+    /// around at most one other IFS character makes a single delimiter, so with `IFS=" :"`
+    /// both `a : b` and `a:b` give `a` and `b`, while `a::b` has an empty field in between.
+    fn split(&mut self, text: &str, ifs: &str) {
+        for ch in text.chars() {
+            if !ifs.contains(ch) {
+                self.push(ch, false);
+            } else if ch.is_whitespace() {
+                if self.started {
+                    self.delimit();
+                    self.after_whitespace = true;
+                }
+            } else if self.after_whitespace {
+                self.after_whitespace = false;
+            } else {
+                self.started = true;
+                self.delimit();
+            }
+        }
+    }
+
+    fn finish(mut self) -> Vec<Field> {
+        self.delimit();
+        self.done
+    }
+}
+
+struct Expander<'a, 'b> {
+    handler: &'a mut CommandHandler,
+    chars: Peekable<Chars<'b>>,
+    fields: Fields,
+    split: bool,
+    // in an assignment value, a tilde is also expanded after every unquoted `:`
+    assignment: bool,
+}
+
+impl<'a, 'b> Expander<'a, 'b> {
+    fn new(handler: &'a mut CommandHandler, word: &'b str, split: bool) -> Self {
+        Expander {
+            handler,
+            chars: word.chars().peekable(),
+            fields: Fields::default(),
+            split,
+            assignment: false,
+        }
+    }
+
+    fn ifs(&self) -> String {
+        self.handler
+            .variables
+            .get("IFS")
+            .unwrap_or_else(|| IFS_DEFAULT.to_string())
+    }
+
+    fn expand(mut self) -> Result<Vec<Field>, ShellError> {
+        self.tilde();
+        self.expand_chars()?;
+        Ok(self.fields.finish())
+    }
+
+    fn expand_chars(&mut self) -> Result<(), ShellError> {
+        while let Some(ch) = self.chars.next() {
+            match ch {
+                '\'' => {
+                    self.fields.started = true;
+                    for ch in self.chars.by_ref() {
+                        if ch == '\'' {
+                            break;
+                        }
+                        self.fields.push(ch, true);
+                    }
+                }
+                '"' => self.double_quoted()?,
+                '\\' => match self.chars.next() {
+                    Some('\n') => {}
+                    Some(ch) => self.fields.push(ch, true),
+                    None => self.fields.push('\\', false),
+                },
+                '$' => self.dollar(false)?,
+                '`' => self.backquoted(false)?,
+                ':' if self.assignment => {
+                    self.fields.push(ch, false);
+                    self.tilde();
+                }
+                _ => self.fields.push(ch, false),
+            }
+        }
+        Ok(())
+    }
+
+    fn double_quoted(&mut self) -> Result<(), ShellError> {
+        let mut saw_at = false;
+        let mut only_at = true;
+        while let Some(ch) = self.chars.next() {
+            match ch {
+                '"' => break,
+                '\\' => match self.chars.next() {
+                    Some('\n') => {}
+                    Some(ch @ ('$' | '`' | '"' | '\\')) => self.fields.push(ch, true),
+                    Some(ch) => {
+                        self.fields.push('\\', true);
+                        self.fields.push(ch, true);
+                    }
+                    None => self.fields.push('\\', true),
+                },
+                '$' if self.chars.peek() == Some(&'@') => {
+                    self.chars.next();
+                    self.positional_at(true);
+                    saw_at = true;
+                    continue;
+                }
+                '$' => self.dollar(true)?,
+                '`' => self.backquoted(true)?,
+                _ => self.fields.push(ch, true),
+            }
+            only_at = false;
+        }
+        // `""` is an empty field, but `"$@"` without positional parameters is no field at all
+        if !saw_at || !only_at {
+            self.fields.started = true;
+        }
+        Ok(())
+    }
+
+    fn dollar(&mut self, quoted: bool) -> Result<(), ShellError> {
+        match self.chars.peek().copied() {
+            Some('{') => {
+                self.chars.next();
+                self.braced(quoted)
+            }
+            Some('(') => {
+                let mut text = String::new();
+                lexer::read_substitution('$', &mut self.chars, &mut text);
+                if let Some(expression) = text
+                    .strip_prefix("$((")
+                    .and_then(|text| text.strip_suffix("))"))
+                {
+                    return self.arithmetic(expression, quoted);
+                }
+                let script = text.strip_prefix("$(").unwrap_or(&text);
+                let script = script.strip_suffix(')').unwrap_or(script);
+                self.command_substitution(script, quoted)
+            }
+            Some('\'') if !quoted => {
+                self.chars.next();
+                let mut text = String::new();
+                while let Some(ch) = self.chars.next() {
+                    match ch {
+                        '\'' => break,
+                        '\\' => {
+                            text.push(ch);
+                            text.extend(self.chars.next());
+                        }
+                        _ => text.push(ch),
+                    }
+                }
+                self.fields.started = true;
+                self.fields.push_str(&lexer::decode_ansi_c(&text), true);
+                Ok(())
+            }
+            // `$"..."` reads as a plain double-quoted string
+            Some('"') if !quoted => Ok(()),
+            Some('@') => {
+                self.chars.next();
+                self.positional_at(quoted);
+                Ok(())
+            }
+            Some('*') => {
+                self.chars.next();
+                self.positional_star(quoted);
+                Ok(())
+            }
+            Some(ch @ ('?' | '$' | '!' | '#' | '0'..='9')) => {
+                self.chars.next();
+                let value = self.handler.variables.get(&ch.to_string());
+                self.push_value(value.unwrap_or_default(), quoted);
+                Ok(())
+            }
+            Some(ch) if ch == '_' || ch.is_ascii_alphabetic() => {
+                let name = self.name();
+                let value = self.handler.variables.get(&name);
+                self.push_value(value.unwrap_or_default(), quoted);
+                Ok(())
+            }
+            _ => {
+                self.fields.push('$', quoted);
+                Ok(())
+            }
+        }
+    }
+
+    /// `` `command` ``: a backslash only escapes `$`, `` ` `` and `\` inside backquotes.
+    fn backquoted(&mut self, quoted: bool) -> Result<(), ShellError> {
+        let mut script = String::new();
+        while let Some(ch) = self.chars.next() {
+            match ch {
+                '`' => break,
+                '\\' => match self.chars.next_if(|&ch| matches!(ch, '$' | '`' | '\\')) {
+                    Some(escaped) => script.push(escaped),
+                    None => script.push(ch),
+                },
+                _ => script.push(ch),
+            }
+        }
+        self.command_substitution(&script, quoted)
+    }
+
+    /// `$((expression))`: the expression is expanded like a double-quoted word first.
+    fn arithmetic(&mut self, expression: &str, quoted: bool) -> Result<(), ShellError> {
+        let value = expand_arithmetic(self.handler, expression)?;
+        self.push_value(value.to_string(), quoted);
+        Ok(())
+    }
+
+    fn command_substitution(&mut self, script: &str, quoted: bool) -> Result<(), ShellError> {
+        let output = self.handler.substitute(script)?;
+        self.push_value(output, quoted);
+        Ok(())
+    }
+
+    /// Tilde expansion of a leading `~`, `~user`, `~+` or `~-` up to the next `/`; the
+    /// result is never split or globbed. An unknown user or unset variable leaves it as is.
+    fn tilde(&mut self) {
+        if self.chars.peek() != Some(&'~') {
+            return;
+        }
+        let assignment = self.assignment;
+        let prefix: String = self
+            .chars
+            .clone()
+            .skip(1)
+            .take_while(|&ch| ch != '/' && !(assignment && ch == ':'))
+            .collect();
+        if prefix.contains(['\'', '"', '\\', '$', '`']) {
+            return;
+        }
+        let variables = &self.handler.variables;
+        let home = match prefix.as_str() {
+            "" => variables.get("HOME"),
+            "+" => variables.get("PWD"),
+            "-" => variables.get("OLDPWD"),
+            user => utils::user_home(user),
+        };
+        let Some(home) = home else {
+            return;
+        };
+        for _ in 0..=prefix.chars().count() {
+            self.chars.next();
+        }
+        self.fields.started = true;
+        self.fields.push_str(&home, true);
+    }
+
+    fn name(&mut self) -> String {
+        let mut name = String::new();
+        while let Some(ch) = self
+            .chars
+            .next_if(|&ch| ch == '_' || ch.is_ascii_alphanumeric())
+        {
+            name.push(ch);
+        }
+        name
+    }
+
+    /// Reads the body of `${...}` up to its closing brace; quotes and nested expansions are
+    /// kept as they are for the operator words.
+    fn braced_text(&mut self) -> String {
+        let mut text = String::new();
+        let mut depth = 0;
+        while let Some(ch) = self.chars.next() {
+            match ch {
+                '}' if depth == 0 => break,
+                '}' => depth -= 1,
+                '{' if text.ends_with('$') => depth += 1,
+                '\\' => {
+                    text.push(ch);
+                    if let Some(next_ch) = self.chars.next() {
+                        text.push(next_ch);
+                    }
+                    continue;
+                }
+                '\'' | '"' => {
+                    text.push(ch);
+                    while let Some(next_ch) = self.chars.next() {
+                        text.push(next_ch);
+                        if next_ch == ch {
+                            break;
+                        }
+                        if next_ch == '\\'
+                            && ch == '"'
+                            && let Some(escaped) = self.chars.next()
+                        {
+                            text.push(escaped);
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            text.push(ch);
+        }
+        text
+    }
+
+    /// The value of a parameter, where `$@` and `$*` count as unset without positionals.
+    fn parameter(&self, name: &str) -> Option<String> {
+        match name {
+            "@" | "*" if self.handler.variables.positional.is_empty() => None,
+            _ => self.handler.variables.get(name),
+        }
+    }
+
+    fn push_parameter(&mut self, name: &str, value: Option<String>, quoted: bool) {
+        match name {
+            "@" => self.positional_at(quoted),
+            "*" => self.positional_star(quoted),
+            _ => self.push_value(value.unwrap_or_default(), quoted),
+        }
+    }
+
+    /// `${parameter}` and its operators: `${#p}`, `${p:-w}`, `${p:=w}`, `${p:?w}`, `${p:+w}`
+    /// (each also without the colon), `${p#w}`, `${p##w}`, `${p%w}`, `${p%%w}`, `${p/w/r}`
+    /// and `${p:offset:length}`.
+    fn braced(&mut self, quoted: bool) -> Result<(), ShellError> {
+        let text = self.braced_text();
+        let bad_substitution = || ShellError::BadSubstitution(format!("${{{}}}", text));
+        if let Some(name) = text.strip_prefix('#').filter(|name| !name.is_empty()) {
+            if !split_parameter(name).1.is_empty() {
+                return Err(bad_substitution());
+            }
+            let length = match name {
+                "@" | "*" => self.handler.variables.positional.len(),
+                _ => self.parameter(name).unwrap_or_default().chars().count(),
+            };
+            self.push_value(length.to_string(), quoted);
+            return Ok(());
+        }
+
+        let (name, op) = split_parameter(&text);
+        if name.is_empty() {
+            return Err(bad_substitution());
+        }
+        let value = self.parameter(name);
+        let (colon, op) = match op.strip_prefix(':') {
+            Some(rest) if rest.starts_with(['-', '=', '?', '+']) => (true, rest),
+            Some(rest) => return self.substring(name, value, rest, quoted),
+            None => (false, op),
+        };
+        let Some(op_ch) = op.chars().next() else {
+            self.push_parameter(name, value, quoted);
+            return Ok(());
+        };
+        if !matches!(op_ch, '-' | '=' | '?' | '+' | '#' | '%' | '/') {
+            return Err(bad_substitution());
+        }
+        let word = &op[op_ch.len_utf8()..];
+        // with a colon, an empty value counts as unset
+        let use_word = match &value {
+            None => true,
+            Some(value) => colon && value.is_empty(),
+        };
+        match op_ch {
+            '-' if use_word => self.push_word(word, quoted)?,
+            '-' => self.push_parameter(name, value, quoted),
+            '=' if use_word => {
+                if !Variables::is_name(name) {
+                    return Err(bad_substitution());
+                }
+                let value = expand_single(self.handler, word)?;
+                self.handler.set_var(name, &value);
+                self.push_value(value, quoted);
+            }
+            '=' => self.push_parameter(name, value, quoted),
+            '?' if use_word => {
+                let message = match word {
+                    "" => "parameter null or not set".to_string(),
+                    _ => expand_single(self.handler, word)?,
+                };
+                return Err(ShellError::ParameterNotSet {
+                    name: name.to_string(),
+                    message,
+                });
+            }
+            '?' => self.push_parameter(name, value, quoted),
+            '+' if use_word => {}
+            '+' => self.push_word(word, quoted)?,
+            '#' | '%' => {
+                let longest = word.starts_with(op_ch);
+                let word = if longest { &word[1..] } else { word };
+                let pattern = self.pattern(word)?;
+                let value: Vec<char> = value.unwrap_or_default().chars().collect();
+                let stripped = match op_ch {
+                    '#' => remove_prefix(&value, &pattern, longest),
+                    _ => remove_suffix(&value, &pattern, longest),
+                };
+                self.push_value(stripped, quoted);
+            }
+            '/' => {
+                let (mode, word) = match word.chars().next() {
+                    Some(ch @ ('/' | '#' | '%')) => (Some(ch), &word[1..]),
+                    _ => (None, word),
+                };
+                let (word, replacement) = split_unquoted(word, '/');
+                let pattern = self.pattern(word)?;
+                let replacement = expand_single(self.handler, replacement)?;
+                let value: Vec<char> = value.unwrap_or_default().chars().collect();
+                let replaced = replace(&value, &pattern, &replacement, mode);
+                self.push_value(replaced, quoted);
+            }
+            _ => return Err(bad_substitution()),
+        }
+        Ok(())
+    }
+
+    /// `${parameter:offset}` and `${parameter:offset:length}`; for `@` and `*` the offset and
+    /// length count positional parameters instead of characters.
+    fn substring(
+        &mut self,
+        name: &str,
+        value: Option<String>,
+        range: &str,
+        quoted: bool,
+    ) -> Result<(), ShellError> {
+        let (offset, length) = match split_unquoted(range, ':') {
+            (offset, "") if !range.contains(':') => (offset, None),
+            (offset, length) => (offset, Some(length)),
+        };
+        let offset = self.number(offset)?;
+        let length = length.map(|length| self.number(length)).transpose()?;
+        if name == "@" || name == "*" {
+            let mut params = vec![self.handler.variables.arg0.clone()];
+            params.extend(self.handler.variables.positional.iter().cloned());
+            // `${@:1}` starts at `$1`; a negative offset counts back from the last one
+            let (start, end) = slice_bounds(params.len(), offset, length);
+            let start = if offset < 0 { start.max(1) } else { start };
+            let params = params[start..end.max(start)].to_vec();
+            match name {
+                "@" => self.push_list(params, quoted),
+                _ => self.push_joined(params, quoted),
+            }
+            return Ok(());
+        }
+        let value: Vec<char> = value.unwrap_or_default().chars().collect();
+        let (start, end) = slice_bounds(value.len(), offset, length);
+        let value = value[start..end.max(start)].iter().collect();
+        self.push_value(value, quoted);
+        Ok(())
+    }
+
+    fn number(&mut self, text: &str) -> Result<i64, ShellError> {
+        let text = expand_single(self.handler, text)?;
+        arith::evaluate(self.handler, &text)
+    }
+
+    /// Expands an operator word in place of the parameter, merging its fields into the
+    /// current ones.
+    fn push_word(&mut self, word: &str, quoted: bool) -> Result<(), ShellError> {
+        let mut expander = Expander::new(self.handler, word, self.split && !quoted);
+        if quoted {
+            // inside double quotes the word is expanded as if it were double-quoted too
+            while expander.chars.peek().is_some() {
+                expander.double_quoted()?;
+            }
+        } else {
+            expander.tilde();
+            expander.expand_chars()?;
+        }
+        for (i, field) in expander.fields.finish().into_iter().enumerate() {
+            if i > 0 {
+                self.fields.delimit();
+            }
+            self.fields.started = true;
+            for (ch, quoted) in field {
+                self.fields.push(ch, quoted);
+            }
+        }
+        Ok(())
+    }
+
+    /// Expands an operator word into a pattern; quoted parts of it match literally.
+    fn pattern(&mut self, word: &str) -> Result<Pattern, ShellError> {
+        expand_pattern(self.handler, word)
+    }
+
+    fn push_value(&mut self, value: String, quoted: bool) {
+        if !quoted && self.split {
+            let ifs = self.ifs();
+            self.fields.split(&value, &ifs);
+        } else {
+            self.fields.push_str(&value, quoted);
+        }
+    }
+
+    /// `$@`: every positional parameter becomes its own field.
+    fn positional_at(&mut self, quoted: bool) {
+        let params = self.handler.variables.positional.clone();
+        self.push_list(params, quoted);
+    }
+
+    fn push_list(&mut self, params: Vec<String>, quoted: bool) {
+        for (i, param) in params.into_iter().enumerate() {
+            if i > 0 {
+                if quoted || self.split {
+                    self.fields.started = true;
+                    self.fields.delimit();
+                } else {
+                    self.fields.push(' ', false);
+                }
+            }
+            self.push_value(param, quoted);
+        }
+    }
+
+    /// `$*`: the positional parameters joined by the first character of `IFS` when quoted.
+    fn positional_star(&mut self, quoted: bool) {
+        let params = self.handler.variables.positional.clone();
+        self.push_joined(params, quoted);
+    }
+
+    fn push_joined(&mut self, params: Vec<String>, quoted: bool) {
+        if !quoted {
+            self.push_list(params, false);
+            return;
+        }
+        let separator = self
+            .ifs()
+            .chars()
+            .next()
+            .map(String::from)
+            .unwrap_or_default();
+        self.fields.push_str(&params.join(&separator), true);
+    }
+}
+
+/// Splits `${...}` text into the parameter name and the operator that follows it.
+fn split_parameter(text: &str) -> (&str, &str) {
+    let end = match text.chars().next() {
+        Some('@' | '*' | '#' | '?' | '$' | '!') => 1,
+        Some(ch) if ch.is_ascii_digit() => text
+            .find(|ch: char| !ch.is_ascii_digit())
+            .unwrap_or(text.len()),
+        Some(ch) if ch == '_' || ch.is_ascii_alphabetic() => text
+            .find(|ch: char| ch != '_' && !ch.is_ascii_alphanumeric())
+            .unwrap_or(text.len()),
+        _ => 0,
+    };
+    text.split_at(end)
+}
+
+/// Splits `text` at the first `separator` that is not quoted or escaped.
+fn split_unquoted(text: &str, separator: char) -> (&str, &str) {
+    let mut quote: Option<char> = None;
+    let mut chars = text.char_indices();
+    while let Some((i, ch)) = chars.next() {
+        match (quote, ch) {
+            (None, '\\') | (Some('"'), '\\') => {
+                chars.next();
+            }
+            (None, '\'' | '"') => quote = Some(ch),
+            (Some(q), _) if q == ch => quote = None,
+            (None, _) if ch == separator => return (&text[..i], &text[i + 1..]),
+            _ => {}
+        }
+    }
+    (text, "")
+}
+
+/// Resolves a bash-style offset and length against `len` items into a clamped range.
+fn slice_bounds(len: usize, offset: i64, length: Option<i64>) -> (usize, usize) {
+    let len = len as i64;
+    let start = if offset < 0 { len + offset } else { offset };
+    if start < 0 || start > len {
+        return (0, 0);
+    }
+    let end = match length {
+        None => len,
+        Some(length) if length < 0 => len + length,
+        Some(length) => start + length,
+    };
+    (start as usize, end.clamp(0, len) as usize)
+}
+
+fn remove_prefix(value: &[char], pattern: &Pattern, longest: bool) -> String {
+    let mut lengths: Vec<usize> = (0..=value.len()).collect();
+    if longest {
+        lengths.reverse();
+    }
+    match lengths
+        .into_iter()
+        .find(|&len| pattern.matches(&value[..len]))
+    {
+        Some(len) => value[len..].iter().collect(),
+        None => value.iter().collect(),
+    }
+}
+
+fn remove_suffix(value: &[char], pattern: &Pattern, longest: bool) -> String {
+    let mut starts: Vec<usize> = (0..=value.len()).collect();
+    if !longest {
+        starts.reverse();
+    }
+    match starts
+        .into_iter()
+        .find(|&start| pattern.matches(&value[start..]))
+    {
+        Some(start) => value[..start].iter().collect(),
+        None => value.iter().collect(),
+    }
+}
+
+/// `${p/w/r}` replaces the first longest match, `${p//w/r}` every match, and `${p/#w/r}`
+/// and `${p/%w/r}` a match anchored at the start or end.
+fn replace(value: &[char], pattern: &Pattern, replacement: &str, mode: Option<char>) -> String {
+    let text: String = value.iter().collect();
+    match mode {
+        Some('#') => match (0..=value.len())
+            .rev()
+            .find(|&end| pattern.matches(&value[..end]))
+        {
+            Some(end) => format!("{}{}", replacement, value[end..].iter().collect::<String>()),
+            None => text,
+        },
+        Some('%') => match (0..=value.len()).find(|&start| pattern.matches(&value[start..])) {
+            Some(start) => format!(
+                "{}{}",
+                value[..start].iter().collect::<String>(),
+                replacement
+            ),
+            None => text,
+        },
+        _ => {
+            let all = mode == Some('/');
+            let mut replaced = String::new();
+            let mut done = false;
+            let mut i = 0;
+            while i < value.len() {
+                if !done && !pattern.is_empty() {
+                    let end = (i + 1..=value.len())
+                        .rev()
+                        .find(|&end| pattern.matches(&value[i..end]));
+                    if let Some(end) = end {
+                        replaced.push_str(replacement);
+                        i = end;
+                        done = !all;
+                        continue;
+                    }
+                }
+                replaced.push(value[i]);
+                i += 1;
+            }
+            replaced
+        }
+    }
+}
+
+fn to_string(field: Field) -> String {
+    field.into_iter().map(|(ch, _)| ch).collect()
+}
+
+/// Skips over the quoted text, escape or `${...}`/`$(...)` starting at `chars[i]`, returning
+/// the index just past it, or `None` if `chars[i]` starts none of those.
+fn skip_quoted(chars: &[char], i: usize) -> Option<usize> {
+    let (open, close) = match (chars[i], chars.get(i + 1)) {
+        ('\\', _) => return Some((i + 2).min(chars.len())),
+        ('\'', _) => {
+            let end = chars[i + 1..].iter().position(|&ch| ch == '\'');
+            return Some(end.map_or(chars.len(), |end| i + end + 2));
+        }
+        ('"', _) => {
+            let mut j = i + 1;
+            while j < chars.len() && chars[j] != '"' {
+                j += if chars[j] == '\\' { 2 } else { 1 };
+            }
+            return Some((j + 1).min(chars.len()));
+        }
+        ('$', Some('{')) => ('{', '}'),
+        ('$', Some('(')) => ('(', ')'),
+        _ => return None,
+    };
+    let mut depth = 0;
+    let mut j = i + 1;
+    while j < chars.len() {
+        if let Some(next) = skip_quoted(chars, j) {
+            j = next;
+            continue;
+        }
+        if chars[j] == open {
+            depth += 1;
+        } else if chars[j] == close {
+            depth -= 1;
+            if depth == 0 {
+                return Some(j + 1);
+            }
+        }
+        j += 1;
+    }
+    Some(chars.len())
+}
+
+/// Finds the `}` matching the `{` at `open`, along with the commas directly inside it.
+fn find_brace_close(chars: &[char], open: usize) -> Option<(usize, Vec<usize>)> {
+    let mut commas = Vec::new();
+    let mut depth = 0;
+    let mut i = open + 1;
+    while i < chars.len() {
+        if let Some(next) = skip_quoted(chars, i) {
+            i = next;
+            continue;
+        }
+        match chars[i] {
+            '{' => depth += 1,
+            '}' if depth == 0 => return Some((i, commas)),
+            '}' => depth -= 1,
+            ',' if depth == 0 => commas.push(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+/// The items of a `{x..y}` or `{x..y..step}` sequence over integers or single characters.
+fn brace_sequence(body: &str) -> Option<Vec<String>> {
+    let parts: Vec<&str> = body.split("..").collect();
+    let (first, last, step) = match parts[..] {
+        [first, last] => (first, last, 1),
+        [first, last, step] => (first, last, step.parse::<i64>().ok()?),
+        _ => return None,
+    };
+    let step = step.unsigned_abs().max(1) as usize;
+    if let (Ok(start), Ok(end)) = (first.parse::<i64>(), last.parse::<i64>()) {
+        // a leading zero on either end pads every item to the same width
+        let padded = |text: &str| text.trim_start_matches('-').starts_with('0') && text.len() > 1;
+        let width = if padded(first) || padded(last) {
+            first.len().max(last.len())
+        } else {
+            0
+        };
+        let items: Vec<i64> = if start <= end {
+            (start..=end).step_by(step).collect()
+        } else {
+            (end..=start).rev().step_by(step).collect()
+        };
+        return Some(
+            items
+                .into_iter()
+                .map(|item| format!("{:0width$}", item, width = width))
+                .collect(),
+        );
+    }
+    let mut first = first.chars();
+    let mut last = last.chars();
+    match (first.next(), first.next(), last.next(), last.next()) {
+        // like bash, only letters make a character range, so `{1..a}` stays as it is
+        (Some(start), None, Some(end), None)
+            if start.is_ascii_alphabetic() && end.is_ascii_alphabetic() =>
+        {
+            let items: Vec<char> = if start <= end {
+                (start..=end).step_by(step).collect()
+            } else {
+                (end..=start).rev().step_by(step).collect()
+            };
+            // the items go back into a raw word, where `{Z..a}`'s punctuation must stay literal
+            let item = |ch: char| {
+                if ch.is_ascii_alphanumeric() {
+                    ch.to_string()
+                } else {
+                    format!("\\{}", ch)
+                }
+            };
+            Some(items.into_iter().map(item).collect())
+        }
+        _ => None,
+    }
+}
+
+/// Brace expansion on a raw word: `a{b,c}d` becomes `abd acd` and `{1..3}` becomes
+/// `1 2 3`. Quoted braces and forms that are not valid expansions are left as they are.
+pub fn expand_braces(word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if let Some(next) = skip_quoted(&chars, i) {
+            i = next;
+            continue;
+        }
+        if chars[i] != '{' {
+            i += 1;
+            continue;
+        }
+        if let Some((close, commas)) = find_brace_close(&chars, i) {
+            let text = |range: &[char]| range.iter().collect::<String>();
+            let items = if commas.is_empty() {
+                brace_sequence(&text(&chars[i + 1..close]))
+            } else {
+                let mut bounds = vec![i];
+                bounds.extend(&commas);
+                bounds.push(close);
+                Some(
+                    bounds
+                        .windows(2)
+                        .map(|bound| text(&chars[bound[0] + 1..bound[1]]))
+                        .collect(),
+                )
+            };
+            if let Some(items) = items {
+                let (prefix, suffix) = (text(&chars[..i]), text(&chars[close + 1..]));
+                return items
+                    .iter()
+                    .flat_map(|item| expand_braces(&format!("{}{}{}", prefix, item, suffix)))
+                    .collect();
+            }
+        }
+        i += 1;
+    }
+    vec![word.to_string()]
+}
+
+/// Expands `word` into fields: brace expansion, parameter expansion, field splitting,
+/// pathname expansion and quote removal. A pattern that matches nothing is kept as it is,
+/// unless `nullglob` or `failglob` say otherwise.
+pub fn expand_word(handler: &mut CommandHandler, word: &str) -> Result<Vec<String>, ShellError> {
+    let mut fields = Vec::new();
+    for word in expand_braces(word) {
+        fields.extend(Expander::new(handler, &word, true).expand()?);
+    }
+    let options = &handler.glob_options;
+    let mut words = Vec::new();
+    for field in fields {
+        match glob::glob(&field, options) {
+            Some(paths) if !paths.is_empty() => words.extend(paths),
+            Some(_) if options.failglob => return Err(ShellError::NoMatch(to_string(field))),
+            Some(_) if options.nullglob => {}
+            _ => words.push(to_string(field)),
+        }
+    }
+    Ok(words)
+}
+
+/// Expands `word` without field splitting or globbing into a pattern in which quoted
+/// characters match themselves, as for `${x#pattern}` and `case`.
+pub fn expand_pattern(handler: &mut CommandHandler, word: &str) -> Result<Pattern, ShellError> {
+    let fields = Expander::new(handler, word, false).expand()?;
+    let extglob = handler.glob_options.extglob;
+    Ok(Pattern::new(&fields.concat(), extglob))
+}
+
+/// Expands `expression` as if double-quoted and evaluates it, as for `$((...))` and `((...))`.
+pub fn expand_arithmetic(
+    handler: &mut CommandHandler,
+    expression: &str,
+) -> Result<i64, ShellError> {
+    let expression = expand_single(handler, &format!("\"{}\"", expression))?;
+    arith::evaluate(handler, &expression)
+}
+
+/// Expands `word` without field splitting, as for redirection targets and here-strings.
+pub fn expand_single(handler: &mut CommandHandler, word: &str) -> Result<String, ShellError> {
+    let fields = Expander::new(handler, word, false).expand()?;
+    Ok(fields
+        .into_iter()
+        .map(to_string)
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Expands an assignment value: like [`expand_single`], with tilde expansion after `=` and
+/// every `:` as in `PATH=~/bin:~/.local/bin`.
+pub fn expand_assignment(handler: &mut CommandHandler, value: &str) -> Result<String, ShellError> {
+    let mut expander = Expander::new(handler, value, false);
+    expander.assignment = true;
+    let fields = expander.expand()?;
+    Ok(fields
+        .into_iter()
+        .map(to_string)
+        .collect::<Vec<_>>()
+        .join(" "))
+}
+
+/// Expands the body of an unquoted here-document, where quotes are ordinary characters and
+/// a backslash only escapes `$`, `` ` ``, `\` and newline.
+pub fn expand_heredoc(handler: &mut CommandHandler, body: &str) -> Result<String, ShellError> {
+    // a leading `~` is not a tilde expansion here
+    let mut escaped = String::new();
+    if body.starts_with('~') {
+        escaped.push('\\');
+    }
+    let mut chars = body.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' => match chars.peek() {
+                Some('\n') => {
+                    chars.next();
+                }
+                Some(&next_ch @ ('$' | '`' | '\\')) => {
+                    chars.next();
+                    escaped.push('\\');
+                    escaped.push(next_ch);
+                }
+                _ => escaped.push_str("\\\\"),
+            },
+            '\'' | '"' => {
+                escaped.push('\\');
+                escaped.push(ch);
+            }
+            // command substitutions keep their own quoting
+            '$' | '`' if ch == '`' || chars.peek() == Some(&'(') => {
+                lexer::read_substitution(ch, &mut chars, &mut escaped);
+            }
+            _ => escaped.push(ch),
+        }
+    }
+    expand_single(handler, &escaped)
+}
+
+/// Removes quoting from `word` without expanding anything, as for here-document delimiters.
+pub fn remove_quotes(word: &str) -> String {
+    let mut unquoted = String::new();
+    let mut chars = word.chars();
+    let mut quote: Option<char> = None;
+    while let Some(ch) = chars.next() {
+        match (quote, ch) {
+            (None, '\'' | '"') => quote = Some(ch),
+            (Some(q), _) if q == ch => quote = None,
+            (None, '\\') | (Some('"'), '\\') => {
+                if let Some(next_ch) = chars.next() {
+                    unquoted.push(next_ch);
+                }
+            }
+            _ => unquoted.push(ch),
+        }
+    }
+    unquoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn braces(word: &str) -> Vec<String> {
+        expand_braces(word)
+    }
+
+    #[test]
+    fn expands_lists() {
+        assert_eq!(braces("file{,.bak}"), ["file", "file.bak"]);
+        assert_eq!(braces("dir/{src,tests}"), ["dir/src", "dir/tests"]);
+        assert_eq!(braces("{a,b{1,2}}c"), ["ac", "b1c", "b2c"]);
+        assert_eq!(braces("{a,b}{x,y}"), ["ax", "ay", "bx", "by"]);
+    }
+
+    #[test]
+    fn expands_sequences() {
+        assert_eq!(braces("{1..4}"), ["1", "2", "3", "4"]);
+        assert_eq!(braces("{3..1}"), ["3", "2", "1"]);
+        assert_eq!(braces("{1..10..3}"), ["1", "4", "7", "10"]);
+        assert_eq!(braces("{01..10}")[..3], ["01", "02", "03"]);
+        assert_eq!(braces("{01..10}").last().unwrap(), "10");
+        assert_eq!(braces("{a..e..2}"), ["a", "c", "e"]);
+        assert_eq!(braces("{-1..1}"), ["-1", "0", "1"]);
+        assert_eq!(braces("{Z..a}")[1..3], ["\\[", "\\\\"]);
+    }
+
+    fn split(text: &str, ifs: &str) -> Vec<String> {
+        let mut fields = Fields::default();
+        fields.split(text, ifs);
+        fields.finish().into_iter().map(to_string).collect()
+    }
+
+    #[test]
+    fn splits_fields_on_ifs() {
+        assert_eq!(split("  a  b\tc\n", IFS_DEFAULT), ["a", "b", "c"]);
+        assert_eq!(split("a : b", " :"), ["a", "b"]);
+        assert_eq!(split("a:b", " :"), ["a", "b"]);
+        assert_eq!(split("a :  :b", " :"), ["a", "", "b"]);
+        assert_eq!(split("a::b", " :"), ["a", "", "b"]);
+        assert_eq!(split(":a", ":"), ["", "a"]);
+        assert_eq!(split("a: ", " :"), ["a"]);
+        assert_eq!(split("a b", ""), ["a b"]);
+    }
+
+    fn handler() -> CommandHandler {
+        let mut handler = CommandHandler::new();
+        handler.variables.set("x", "héllo");
+        handler.variables.set("path", "/usr/local/lib.so.1");
+        handler.variables.set("empty", "");
+        handler.variables.unset("unset");
+        handler
+    }
+
+    #[test]
+    fn expands_parameter_operators() {
+        let mut handler = handler();
+        let cases = [
+            ("${unset:-d}", "d"),
+            ("${empty:-d}", "d"),
+            ("${empty-d}", ""),
+            ("${x:-d}", "héllo"),
+            ("${unset:+d}", ""),
+            ("${empty+d}", "d"),
+            ("${x:+d}", "d"),
+            ("${x:?}", "héllo"),
+            ("${#x}", "5"),
+            ("${#unset}", "0"),
+            ("${x:1:2}", "él"),
+            ("${x:1}", "éllo"),
+            ("${x: -3}", "llo"),
+            ("${x:1:-1}", "éll"),
+            ("${x#h}", "éllo"),
+            ("${x#*l}", "lo"),
+            ("${x##*l}", "o"),
+            ("${path#*/}", "usr/local/lib.so.1"),
+            ("${path##*/}", "lib.so.1"),
+            ("${path%.*}", "/usr/local/lib.so"),
+            ("${path%%.*}", "/usr/local/lib"),
+            ("${x/é/e}", "hello"),
+            ("${x/l/L}", "héLlo"),
+            ("${x//l/L}", "héLLo"),
+            ("${x/#h/H}", "Héllo"),
+            ("${x/#l/L}", "héllo"),
+            ("${x/%o/O}", "héllO"),
+            ("${x//l}", "héo"),
+        ];
+        for (word, expected) in cases {
+            assert_eq!(
+                expand_single(&mut handler, word).unwrap(),
+                expected,
+                "{}",
+                word
+            );
+        }
+    }
+
+    #[test]
+    fn assigns_default_values() {
+        let mut handler = handler();
+        assert_eq!(expand_single(&mut handler, "${unset:=é}").unwrap(), "é");
+        assert_eq!(handler.variables.get("unset").as_deref(), Some("é"));
+        assert_eq!(expand_single(&mut handler, "${x:=d}").unwrap(), "héllo");
+        assert_eq!(handler.variables.get("x").as_deref(), Some("héllo"));
+    }
+
+    #[test]
+    fn reports_bad_parameters() {
+        let mut handler = handler();
+        let error = expand_single(&mut handler, "${unset:?gone}").unwrap_err();
+        assert!(
+            matches!(error, ShellError::ParameterNotSet { ref name, ref message }
+            if name == "unset" && message == "gone")
+        );
+        let error = expand_single(&mut handler, "${empty:?}").unwrap_err();
+        assert!(matches!(error, ShellError::ParameterNotSet { .. }));
+        let error = expand_single(&mut handler, "${xé}").unwrap_err();
+        assert!(matches!(error, ShellError::BadSubstitution(_)));
+        let error = expand_single(&mut handler, "${x!}").unwrap_err();
+        assert!(matches!(error, ShellError::BadSubstitution(_)));
+    }
+
+    #[test]
+    fn leaves_invalid_forms_alone() {
+        assert_eq!(braces("{a}"), ["{a}"]);
+        assert_eq!(braces("{}"), ["{}"]);
+        assert_eq!(braces("{1..a}"), ["{1..a}"]);
+        assert_eq!(braces("{a,b"), ["{a,b"]);
+        assert_eq!(braces("'{a,b}'"), ["'{a,b}'"]);
+        assert_eq!(braces("\\{a,b}"), ["\\{a,b}"]);
+    }
+}
@@ -0,0 +1,175 @@
+use std::fs;
+
+use crate::pattern::Pattern;
+
+/// Pathname expansion switches toggled with `shopt`.
+#[derive(Debug, Clone)]
+pub struct GlobOptions {
+    pub dotglob: bool,
+    pub extglob: bool,
+    pub failglob: bool,
+    pub globstar: bool,
+    pub nocaseglob: bool,
+    pub nullglob: bool,
+}
+
+impl Default for GlobOptions {
+    fn default() -> Self {
+        GlobOptions {
+            dotglob: false,
+            extglob: true,
+            failglob: false,
+            globstar: true,
+            nocaseglob: false,
+            nullglob: false,
+        }
+    }
+}
+
+impl GlobOptions {
+    pub const NAMES: [&str; 6] = [
+        "dotglob",
+        "extglob",
+        "failglob",
+        "globstar",
+        "nocaseglob",
+        "nullglob",
+    ];
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "dotglob" => Some(&mut self.dotglob),
+            "extglob" => Some(&mut self.extglob),
+            "failglob" => Some(&mut self.failglob),
+            "globstar" => Some(&mut self.globstar),
+            "nocaseglob" => Some(&mut self.nocaseglob),
+            "nullglob" => Some(&mut self.nullglob),
+            _ => None,
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<bool> {
+        self.clone().get_mut(name).map(|value| *value)
+    }
+
+    fn pattern(&self, component: &[(char, bool)]) -> Pattern {
+        let pattern = Pattern::new(component, self.extglob);
+        if self.nocaseglob {
+            pattern.ignore_case()
+        } else {
+            pattern
+        }
+    }
+}
+
+/// Appends `name` to a path built so far, which is empty for the current directory.
+fn join(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else if prefix.ends_with('/') {
+        format!("{}{}", prefix, name)
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+fn is_dir(path: &str) -> bool {
+    fs::metadata(path).is_ok_and(|metadata| metadata.is_dir())
+}
+
+/// The entry names in `dir`; names starting with `.` are left out unless `hidden` is set.
+fn read_names(dir: &str, hidden: bool) -> Vec<String> {
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return Vec::new();
+    };
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| hidden || !name.starts_with('.'))
+        .collect()
+}
+
+/// The entries of `dir` matching `component`; names starting with `.` only match when the
+/// pattern itself starts with a `.` or `dotglob` is set.
+fn read_matches(
+    dir: &str,
+    component: &[(char, bool)],
+    dirs_only: bool,
+    options: &GlobOptions,
+) -> Vec<String> {
+    let pattern = options.pattern(component);
+    let hidden = options.dotglob || matches!(component.first(), Some(('.', _)));
+    read_names(dir, hidden)
+        .into_iter()
+        .filter(|name| pattern.matches(&name.chars().collect::<Vec<_>>()))
+        .map(|name| join(dir, &name))
+        .filter(|path| !dirs_only || is_dir(path))
+        .collect()
+}
+
+/// `**`: `dir` itself and everything below it, or only the directories when more
+/// components follow. Symbolic links to directories are not followed.
+fn read_recursive(dir: &str, dirs_only: bool, options: &GlobOptions) -> Vec<String> {
+    let mut paths = Vec::new();
+    if dirs_only {
+        paths.push(dir.to_string());
+    }
+    let mut pending = vec![dir.to_string()];
+    while let Some(dir) = pending.pop() {
+        for name in read_names(&dir, options.dotglob) {
+            let path = join(&dir, &name);
+            let real_dir = fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir());
+            if real_dir {
+                pending.push(path.clone());
+            }
+            if !dirs_only || real_dir {
+                paths.push(path);
+            }
+        }
+    }
+    paths
+}
+
+/// Expands a field containing unquoted pattern characters into the sorted paths it matches,
+/// or `None` when the field is not a pattern at all.
+pub fn glob(field: &[(char, bool)], options: &GlobOptions) -> Option<Vec<String>> {
+    if !options.pattern(field).has_magic() {
+        return None;
+    }
+    let components: Vec<&[(char, bool)]> = field.split(|&(ch, _)| ch == '/').collect();
+    let mut paths = vec![match components.first() {
+        Some([]) => "/".to_string(),
+        _ => String::new(),
+    }];
+    for (i, component) in components.iter().enumerate() {
+        if component.is_empty() {
+            // a trailing slash only matches directories and is kept on the result
+            if i > 0 && i == components.len() - 1 {
+                paths = paths
+                    .into_iter()
+                    .filter(|path| is_dir(path))
+                    .map(|path| format!("{}/", path))
+                    .collect();
+            }
+            continue;
+        }
+        let dirs_only = i + 1 < components.len();
+        if options.globstar && *component == [('*', false), ('*', false)] {
+            paths = paths
+                .iter()
+                .flat_map(|path| read_recursive(path, dirs_only, options))
+                .collect();
+        } else if options.pattern(component).has_magic() {
+            paths = paths
+                .iter()
+                .flat_map(|path| read_matches(path, component, dirs_only, options))
+                .collect();
+        } else {
+            let name: String = component.iter().map(|(ch, _)| ch).collect();
+            paths = paths.iter().map(|path| join(path, &name)).collect();
+        }
+    }
+    paths.retain(|path| !path.is_empty() && fs::symlink_metadata(path).is_ok());
+    paths.sort();
+    paths.dedup();
+    Some(paths)
+}
@@ -0,0 +1,766 @@
+use std::{iter::Peekable, ops::Range, str::Chars};
+
+use crate::{
+    error::{Diagnostic, ShellError, line_column},
+    parse::ParseMode,
+};
+
+const SINGLE_QUOTE: char = '\'';
+const DOUBLE_QUOTE: char = '\"';
+const BACKSLASH: char = '\\';
+const NEWLINE: char = '\n';
+const DOLLAR: char = '$';
+const BACKTICK: char = '`';
+const OPEN_BRACE: char = '{';
+const CLOSE_BRACE: char = '}';
+const OPEN_PAREN: char = '(';
+const CLOSE_PAREN: char = ')';
+const COMMENT: char = '#';
+const EXTGLOB_PREFIXES: [char; 5] = ['?', '*', '+', '@', '!'];
+
+// longest first, so that `<<-` is never read as `<<` followed by `-`
+const OPERATORS: [&str; 22] = [
+    ";;&", "<<<", "<<-", "&>>", "&&", "||", ";;", ";&", "<<", ">>", "<&", ">&", "<>", ">|", "&>",
+    "|", "&", ";", "<", ">", "(", ")",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenKind {
+    /// A word exactly as written, quotes and substitutions included.
+    Word(String),
+    /// The digits directly in front of a redirection operator, as in `2>`.
+    IoNumber(i32),
+    Operator(&'static str),
+    Newline,
+    Eof,
+}
+
+/// A token with the byte offsets it spans in the input.
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Token {
+    /// How the token reads in an error message.
+    pub fn describe(&self) -> String {
+        match &self.kind {
+            TokenKind::Word(word) => format!("token '{}'", word),
+            TokenKind::IoNumber(fd) => format!("token '{}'", fd),
+            TokenKind::Operator(op) => format!("token '{}'", op),
+            TokenKind::Newline => "newline".to_string(),
+            TokenKind::Eof => "end of input".to_string(),
+        }
+    }
+
+    /// Whether this is the unquoted word `word`, as reserved words must be.
+    pub fn is_word(&self, word: &str) -> bool {
+        matches!(&self.kind, TokenKind::Word(text) if text == word)
+    }
+
+    pub fn is_operator(&self, op: &str) -> bool {
+        matches!(self.kind, TokenKind::Operator(found) if found == op)
+    }
+}
+
+/// Splits shell input into tokens on demand, so the parser can switch to reading raw text
+/// for `((...))` and here-document bodies.
+pub struct Lexer {
+    input: String,
+    pos: usize,
+    peeked: Option<Token>,
+    // here-document bodies already read for the line ending at this newline, and where
+    // they end; the newline token skips over them
+    heredoc_skip: Option<(usize, usize)>,
+}
+
+impl Lexer {
+    pub fn new(input: &str) -> Lexer {
+        Lexer {
+            input: input.to_string(),
+            pos: 0,
+            peeked: None,
+            heredoc_skip: None,
+        }
+    }
+
+    /// A syntax error at `span` of the input.
+    pub fn error(&self, message: String, span: Range<usize>) -> ShellError {
+        ShellError::Syntax(Diagnostic::new(message, span, &self.input))
+    }
+
+    /// A syntax error for input that ends before the construct at `span` is complete.
+    pub fn incomplete(&self, message: String, span: Range<usize>) -> ShellError {
+        let mut diagnostic = Diagnostic::new(message, span, &self.input);
+        diagnostic.incomplete = true;
+        ShellError::Syntax(diagnostic)
+    }
+
+    fn column(&self, offset: usize) -> usize {
+        line_column(&self.input, offset).1
+    }
+
+    /// The input from `start` up to the next token, without trailing blanks.
+    pub fn text_since(&mut self, start: usize) -> Result<String, ShellError> {
+        let end = self.peek_token()?.start;
+        Ok(self.input[start..end].trim_end().to_string())
+    }
+
+    pub fn peek_token(&mut self) -> Result<&Token, ShellError> {
+        if self.peeked.is_none() {
+            self.peeked = Some(self.read_token()?);
+        }
+        Ok(self.peeked.as_ref().unwrap())
+    }
+
+    pub fn next_token(&mut self) -> Result<Token, ShellError> {
+        match self.peeked.take() {
+            Some(token) => Ok(token),
+            None => self.read_token(),
+        }
+    }
+
+    /// Forgets a peeked token so that raw reading starts in front of it again.
+    fn unpeek(&mut self) {
+        if let Some(token) = self.peeked.take() {
+            self.pos = token.start;
+        }
+    }
+
+    fn rest(&self) -> &str {
+        &self.input[self.pos..]
+    }
+
+    fn peek_char(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let ch = self.peek_char()?;
+        self.pos += ch.len_utf8();
+        Some(ch)
+    }
+
+    /// Runs a reader that works on a character iterator over the rest of the input, then
+    /// moves past whatever it consumed.
+    fn with_chars(&mut self, read: impl FnOnce(&mut Peekable<Chars>)) {
+        let mut chars_iter = self.input[self.pos..].chars().peekable();
+        read(&mut chars_iter);
+        let remaining: usize = chars_iter.map(char::len_utf8).sum();
+        self.pos = self.input.len() - remaining;
+    }
+
+    fn skip_blanks(&mut self) {
+        loop {
+            if self.rest().starts_with([' ', '\t']) {
+                self.pos += 1;
+            } else if self.rest().starts_with("\\\n") {
+                // a backslash-newline joins lines
+                self.pos += 2;
+            } else {
+                return;
+            }
+        }
+    }
+
+    fn read_token(&mut self) -> Result<Token, ShellError> {
+        self.skip_blanks();
+        // a `#` where a word could start comments out the rest of the line
+        if self.peek_char() == Some(COMMENT) {
+            self.pos = self
+                .rest()
+                .find(NEWLINE)
+                .map_or(self.input.len(), |end| self.pos + end);
+        }
+        let start = self.pos;
+        let kind = match self.peek_char() {
+            None => TokenKind::Eof,
+            Some(NEWLINE) => {
+                self.pos += 1;
+                if let Some((newline, body_end)) = self.heredoc_skip.take()
+                    && newline == start
+                {
+                    self.pos = body_end;
+                }
+                TokenKind::Newline
+            }
+            Some(_) => match OPERATORS.iter().find(|op| self.rest().starts_with(**op)) {
+                Some(op) => {
+                    self.pos += op.len();
+                    TokenKind::Operator(op)
+                }
+                None => {
+                    let word = self.read_word()?;
+                    match word.parse::<i32>() {
+                        Ok(fd)
+                            if word.chars().all(|ch| ch.is_ascii_digit())
+                                && self.rest().starts_with(['<', '>']) =>
+                        {
+                            TokenKind::IoNumber(fd)
+                        }
+                        _ => TokenKind::Word(word),
+                    }
+                }
+            },
+        };
+        Ok(Token {
+            kind,
+            start,
+            end: self.pos,
+        })
+    }
+
+    fn read_word(&mut self) -> Result<String, ShellError> {
+        let mut word = String::new();
+        let mut mode = ParseMode::None;
+        let mut quote_start = self.pos;
+        let mut ansi_c = String::new();
+        while let Some(ch) = self.peek_char() {
+            match mode {
+                ParseMode::None => match ch {
+                    ' ' | '\t' | NEWLINE | '|' | '&' | ';' | '<' | '>' | CLOSE_PAREN => break,
+                    OPEN_PAREN if word.ends_with(EXTGLOB_PREFIXES) => {
+                        self.with_chars(|chars_iter| read_pattern_list(chars_iter, &mut word))
+                    }
+                    OPEN_PAREN => break,
+                    SINGLE_QUOTE | DOUBLE_QUOTE => {
+                        quote_start = self.pos;
+                        self.bump();
+                        word.push(ch);
+                        mode = if ch == SINGLE_QUOTE {
+                            ParseMode::SingleQuote
+                        } else {
+                            ParseMode::DoubleQuote
+                        };
+                    }
+                    BACKSLASH => self.read_escape(&mut word)?,
+                    DOLLAR if self.rest()[1..].starts_with(SINGLE_QUOTE) => {
+                        quote_start = self.pos;
+                        self.pos += 2;
+                        mode = ParseMode::AnsiCQuote;
+                    }
+                    // `$"..."` would be translated for the locale; here it is a plain
+                    // double-quoted string
+                    DOLLAR if self.rest()[1..].starts_with(DOUBLE_QUOTE) => {
+                        self.bump();
+                    }
+                    DOLLAR | BACKTICK => self.read_substitution(ch, &mut word)?,
+                    _ => {
+                        self.bump();
+                        word.push(ch);
+                    }
+                },
+                ParseMode::AnsiCQuote => {
+                    self.bump();
+                    match ch {
+                        SINGLE_QUOTE => {
+                            // the decoded text goes into the word single-quoted, so it is
+                            // taken literally from here on
+                            let decoded = decode_ansi_c(&std::mem::take(&mut ansi_c));
+                            word.push(SINGLE_QUOTE);
+                            word.push_str(&decoded.replace(SINGLE_QUOTE, "'\\''"));
+                            word.push(SINGLE_QUOTE);
+                            mode = ParseMode::None;
+                        }
+                        BACKSLASH => {
+                            ansi_c.push(ch);
+                            if let Some(escaped) = self.bump() {
+                                ansi_c.push(escaped);
+                            }
+                        }
+                        _ => ansi_c.push(ch),
+                    }
+                }
+                ParseMode::SingleQuote => {
+                    self.bump();
+                    word.push(ch);
+                    if ch == SINGLE_QUOTE {
+                        mode = ParseMode::None;
+                    }
+                }
+                ParseMode::DoubleQuote => match ch {
+                    DOUBLE_QUOTE => {
+                        self.bump();
+                        word.push(ch);
+                        mode = ParseMode::None;
+                    }
+                    BACKSLASH => self.read_escape(&mut word)?,
+                    DOLLAR | BACKTICK => self.read_substitution(ch, &mut word)?,
+                    _ => {
+                        self.bump();
+                        word.push(ch);
+                    }
+                },
+            }
+        }
+        let quote = match mode {
+            ParseMode::None => return Ok(word),
+            ParseMode::SingleQuote => "single",
+            ParseMode::DoubleQuote => "double",
+            ParseMode::AnsiCQuote => "ANSI-C",
+        };
+        Err(self.incomplete(
+            format!(
+                "unterminated {} quote starting at column {}",
+                quote,
+                self.column(quote_start)
+            ),
+            quote_start..quote_start + 1,
+        ))
+    }
+
+    /// A `$...` or backquoted substitution, which must be closed before the input ends.
+    fn read_substitution(&mut self, ch: char, word: &mut String) -> Result<(), ShellError> {
+        let start = self.pos;
+        self.bump();
+        let mut closed = true;
+        self.with_chars(|chars_iter| closed = read_substitution(ch, chars_iter, word));
+        if closed {
+            return Ok(());
+        }
+        let opening = &self.input[start..(start + 2).min(self.input.len())];
+        let opening = if ch == BACKTICK { "`" } else { opening };
+        Err(self.incomplete(
+            format!(
+                "unterminated '{}' starting at column {}",
+                opening,
+                self.column(start)
+            ),
+            start..start + opening.len(),
+        ))
+    }
+
+    /// A backslash and the character it escapes; a backslash-newline disappears entirely,
+    /// and a backslash ending the input asks for another line.
+    fn read_escape(&mut self, word: &mut String) -> Result<(), ShellError> {
+        let start = self.pos;
+        self.bump();
+        match self.bump() {
+            Some(NEWLINE) => {}
+            Some(escaped) => {
+                word.push(BACKSLASH);
+                word.push(escaped);
+            }
+            None => {
+                return Err(self.incomplete(
+                    "unexpected end of input after '\\'".to_string(),
+                    start..self.pos,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads `((expression))` when the next command starts with `((`, returning the
+    /// expression between the parentheses.
+    pub fn read_arithmetic(&mut self) -> Result<Option<String>, ShellError> {
+        self.unpeek();
+        self.skip_blanks();
+        if !self.rest().starts_with("((") {
+            return Ok(None);
+        }
+        let start = self.pos;
+        self.pos += 2;
+        let mut expression = String::new();
+        let mut depth = 0;
+        loop {
+            match self.bump() {
+                Some(CLOSE_PAREN) if depth == 0 => {
+                    if self.peek_char() != Some(CLOSE_PAREN) {
+                        return Err(self.error(
+                            "expected '))' to close '(('".to_string(),
+                            self.pos - 1..self.pos,
+                        ));
+                    }
+                    self.pos += 1;
+                    return Ok(Some(expression));
+                }
+                Some(ch) => {
+                    match ch {
+                        OPEN_PAREN => depth += 1,
+                        CLOSE_PAREN => depth -= 1,
+                        _ => {}
+                    }
+                    expression.push(ch);
+                }
+                None => {
+                    return Err(self.incomplete(
+                        format!(
+                            "unterminated '((' starting at column {}",
+                            self.column(start)
+                        ),
+                        start..start + 2,
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Reads the body of a here-document: the lines after the current one (and after the
+    /// bodies of earlier here-documents on it) up to the delimiter line, which the lexer
+    /// then skips. Returns the body and whether the delimiter was found.
+    pub fn read_heredoc_body(&mut self, delimiter: &str, strip_tabs: bool) -> (String, bool) {
+        self.unpeek();
+        let Some(line_end) = self.rest().find(NEWLINE).map(|end| self.pos + end) else {
+            return (String::new(), false);
+        };
+        let body_start = match self.heredoc_skip {
+            Some((newline, body_end)) if newline == line_end => body_end,
+            _ => line_end + 1,
+        };
+        let mut body = String::new();
+        let mut offset = body_start;
+        let mut complete = false;
+        while offset < self.input.len() {
+            let line_end = self.input[offset..]
+                .find(NEWLINE)
+                .map_or(self.input.len(), |end| offset + end);
+            let mut line = &self.input[offset..line_end];
+            offset = (line_end + 1).min(self.input.len());
+            if strip_tabs {
+                line = line.trim_start_matches('\t');
+            }
+            if line == delimiter {
+                complete = true;
+                break;
+            }
+            body.push_str(line);
+            body.push(NEWLINE);
+        }
+        self.heredoc_skip = Some((line_end, offset));
+        (body, complete)
+    }
+}
+
+/// Copies a `$...` or backquoted substitution verbatim into `token`, so that blanks and
+/// operators inside `${...}`, `$(...)` and `` `...` `` do not end the word. Words are kept
+/// unexpanded here; `expand` interprets them when the command runs. Returns whether the
+/// substitution was closed before the input ran out.
+pub fn read_substitution(ch: char, chars_iter: &mut Peekable<Chars>, token: &mut String) -> bool {
+    token.push(ch);
+    let close = match (ch, chars_iter.peek()) {
+        (BACKTICK, _) => BACKTICK,
+        (_, Some(&OPEN_BRACE)) => CLOSE_BRACE,
+        (_, Some(&OPEN_PAREN)) => CLOSE_PAREN,
+        _ => return true,
+    };
+    let open = if ch == BACKTICK {
+        BACKTICK
+    } else {
+        let open = chars_iter.next().unwrap();
+        token.push(open);
+        open
+    };
+    let mut depth = 1;
+    while let Some(ch) = chars_iter.next() {
+        token.push(ch);
+        match ch {
+            BACKSLASH => {
+                if let Some(next_ch) = chars_iter.next() {
+                    token.push(next_ch);
+                }
+            }
+            SINGLE_QUOTE if close != BACKTICK => {
+                for next_ch in chars_iter.by_ref() {
+                    token.push(next_ch);
+                    if next_ch == SINGLE_QUOTE {
+                        break;
+                    }
+                }
+            }
+            DOUBLE_QUOTE if close != BACKTICK => {
+                while let Some(next_ch) = chars_iter.next() {
+                    token.push(next_ch);
+                    match next_ch {
+                        DOUBLE_QUOTE => break,
+                        BACKSLASH => {
+                            if let Some(escaped) = chars_iter.next() {
+                                token.push(escaped);
+                            }
+                        }
+                        DOLLAR | BACKTICK => {
+                            token.pop();
+                            if !read_substitution(next_ch, chars_iter, token) {
+                                return false;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            DOLLAR | BACKTICK if close != BACKTICK => {
+                token.pop();
+                if !read_substitution(ch, chars_iter, token) {
+                    return false;
+                }
+            }
+            _ if ch == close => {
+                depth -= 1;
+                if depth == 0 {
+                    return true;
+                }
+            }
+            _ if ch == open => depth += 1,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Decodes the backslash escapes of a `$'...'` string. The text stops at an escaped NUL,
+/// which cannot be part of a word.
+pub fn decode_ansi_c(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(ch) = chars.next() {
+        if ch != BACKSLASH {
+            decoded.push(ch);
+            continue;
+        }
+        let Some(escape) = chars.next() else {
+            decoded.push(BACKSLASH);
+            break;
+        };
+        let code = match escape {
+            'a' => Some(0x07),
+            'b' => Some(0x08),
+            'e' | 'E' => Some(0x1b),
+            'f' => Some(0x0c),
+            'n' => Some(0x0a),
+            'r' => Some(0x0d),
+            't' => Some(0x09),
+            'v' => Some(0x0b),
+            '\\' | '\'' | '"' | '?' => Some(escape as u32),
+            // `\0NNN` takes up to three more digits after the zero, `\NNN` up to three
+            '0'..='7' => {
+                let max = if escape == '0' { 3 } else { 2 };
+                Some(read_digits(&mut chars, 8, max, escape.to_digit(8).unwrap()))
+            }
+            'x' => read_hex(&mut chars, 2),
+            'u' => read_hex(&mut chars, 4),
+            'U' => read_hex(&mut chars, 8),
+            'c' => chars.next().map(|control| match control {
+                '?' => 0x7f,
+                _ => control.to_ascii_uppercase() as u32 & 0x1f,
+            }),
+            _ => None,
+        };
+        match code {
+            Some(0) => break,
+            Some(code) => decoded.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)),
+            None => {
+                decoded.push(BACKSLASH);
+                decoded.push(escape);
+            }
+        }
+    }
+    decoded
+}
+
+/// Reads up to `max` more digits in `radix`, continuing from `value`.
+fn read_digits(chars: &mut Peekable<Chars>, radix: u32, max: usize, mut value: u32) -> u32 {
+    for _ in 0..max {
+        let Some(digit) = chars.peek().and_then(|ch| ch.to_digit(radix)) else {
+            break;
+        };
+        chars.next();
+        value = value.saturating_mul(radix).saturating_add(digit);
+    }
+    value
+}
+
+/// The code of a `\x`, `\u` or `\U` escape; `None` if no hex digit follows.
+fn read_hex(chars: &mut Peekable<Chars>, max: usize) -> Option<u32> {
+    let first = chars.peek()?.to_digit(16)?;
+    chars.next();
+    Some(read_digits(chars, 16, max - 1, first))
+}
+
+/// The `(...)` of an extglob pattern list such as `@(a|b)`, kept in the word as written.
+fn read_pattern_list(chars_iter: &mut Peekable<Chars>, token: &mut String) {
+    let mut depth = 0;
+    while let Some(ch) = chars_iter.next() {
+        token.push(ch);
+        match ch {
+            BACKSLASH => {
+                if let Some(next_ch) = chars_iter.next() {
+                    token.push(next_ch);
+                }
+            }
+            OPEN_PAREN => depth += 1,
+            CLOSE_PAREN => {
+                depth -= 1;
+                if depth == 0 {
+                    return;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        let mut lexer = Lexer::new(input);
+        let mut tokens = Vec::new();
+        loop {
+            let token = lexer.next_token().unwrap();
+            if token.kind == TokenKind::Eof {
+                return tokens;
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn kinds(input: &str) -> Vec<TokenKind> {
+        tokens(input).into_iter().map(|token| token.kind).collect()
+    }
+
+    fn word(text: &str) -> TokenKind {
+        TokenKind::Word(text.to_string())
+    }
+
+    fn error(input: &str) -> Diagnostic {
+        let mut lexer = Lexer::new(input);
+        loop {
+            match lexer.next_token() {
+                Ok(token) if token.kind == TokenKind::Eof => panic!("no error in {:?}", input),
+                Ok(_) => {}
+                Err(ShellError::Syntax(diagnostic)) => return diagnostic,
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn splits_words_and_operators() {
+        assert_eq!(
+            kinds("cat<in|wc -l&&echo ok;;&"),
+            [
+                word("cat"),
+                TokenKind::Operator("<"),
+                word("in"),
+                TokenKind::Operator("|"),
+                word("wc"),
+                word("-l"),
+                TokenKind::Operator("&&"),
+                word("echo"),
+                word("ok"),
+                TokenKind::Operator(";;&"),
+            ]
+        );
+        assert_eq!(
+            kinds("cat <<-EOF"),
+            [word("cat"), TokenKind::Operator("<<-"), word("EOF")]
+        );
+    }
+
+    #[test]
+    fn reads_io_numbers_only_before_redirections() {
+        assert_eq!(
+            kinds("cmd 2>&1 >f 3 >g"),
+            [
+                word("cmd"),
+                TokenKind::IoNumber(2),
+                TokenKind::Operator(">&"),
+                word("1"),
+                TokenKind::Operator(">"),
+                word("f"),
+                word("3"),
+                TokenKind::Operator(">"),
+                word("g"),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_quotes_and_substitutions_in_words() {
+        assert_eq!(
+            kinds(r#"echo "a b"'c d' $(x | y) `z` a\ b"#),
+            [
+                word("echo"),
+                word(r#""a b"'c d'"#),
+                word("$(x | y)"),
+                word("`z`"),
+                word(r"a\ b"),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_comments_at_the_start_of_a_word() {
+        assert_eq!(
+            kinds("echo a#b $# # comment\nx"),
+            [
+                word("echo"),
+                word("a#b"),
+                word("$#"),
+                TokenKind::Newline,
+                word("x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn records_byte_spans() {
+        let spans: Vec<(usize, usize)> = tokens("echo  'é' >out")
+            .iter()
+            .map(|token| (token.start, token.end))
+            .collect();
+        assert_eq!(spans, [(0, 4), (6, 10), (11, 12), (12, 15)]);
+    }
+
+    #[test]
+    fn reports_unterminated_quotes_as_incomplete() {
+        let diagnostic = error("echo 'abc");
+        assert!(diagnostic.incomplete);
+        assert_eq!(
+            diagnostic.message,
+            "unterminated single quote starting at column 6"
+        );
+        assert_eq!(diagnostic.span.start, 5);
+        assert!(error("echo \"a $(b").incomplete);
+        assert!(error("echo a\\").incomplete);
+    }
+
+    #[test]
+    fn decodes_ansi_c_escapes() {
+        assert_eq!(decode_ansi_c(r"a\tb\nc"), "a\tb\nc");
+        assert_eq!(
+            decode_ansi_c(r"\a\b\e\E\f\r\v"),
+            "\x07\x08\x1b\x1b\x0c\r\x0b"
+        );
+        assert_eq!(decode_ansi_c(r#"\\ \' \" \?"#), r#"\ ' " ?"#);
+        assert_eq!(decode_ansi_c(r"\x41\x4a2"), "AJ2");
+        assert_eq!(decode_ansi_c(r"\u00e9\U0001F600"), "é😀");
+        assert_eq!(decode_ansi_c(r"\101\0101\1018"), "AAA8");
+        assert_eq!(decode_ansi_c(r"\cA\c?"), "\x01\x7f");
+        assert_eq!(decode_ansi_c(r"\q"), r"\q");
+    }
+
+    #[test]
+    fn ansi_c_nul_ends_the_string() {
+        assert_eq!(decode_ansi_c(r"ab\0cd"), "ab");
+        assert_eq!(decode_ansi_c(r"ab\x00cd"), "ab");
+    }
+
+    #[test]
+    fn reads_ansi_c_and_locale_strings_as_quoted_words() {
+        assert_eq!(
+            kinds(r"echo $'a\'b\n'"),
+            [word("echo"), word("'a'\\''b\n'")]
+        );
+        assert_eq!(kinds(r#"echo $"x y""#), [word("echo"), word(r#""x y""#)]);
+        let diagnostic = error("echo $'abc");
+        assert!(diagnostic.incomplete);
+        assert_eq!(
+            diagnostic.message,
+            "unterminated ANSI-C quote starting at column 6"
+        );
+    }
+}
@@ -1,20 +1,32 @@
 #[allow(unused_imports)]
 use std::io::{self, Write};
+use std::{env, fs, process};
 
 use crate::{
-    parse::{CommandHandler, ParsedCommand},
+    error::ShellError,
+    parse::{CommandHandler, CommandList},
     shellio::IOHandler,
 };
+pub mod arith;
 pub mod command;
 pub mod error;
+pub mod expand;
+pub mod glob;
+pub mod lexer;
 pub mod parse;
+pub mod pattern;
 pub mod shellio;
 pub mod utils;
+pub mod variable;
 
 fn main() {
     let mut io_handler = IOHandler::new();
     let mut command_handler = CommandHandler::new();
+    if let Some(script) = env::args().nth(1) {
+        process::exit(run_script(&mut command_handler, &io_handler, &script));
+    }
     loop {
+        command_handler.reap_jobs();
         IOHandler::print_prompt();
         exec_command(&mut command_handler, &mut io_handler);
         io_handler.reset();
@@ -25,38 +37,78 @@ fn exec_command(command_handler: &mut CommandHandler, io_handler: &mut IOHandler
     let mut raw_command: String;
     match IOHandler::get_raw_command() {
         Ok(mut r_cmd) => raw_command = std::mem::take(&mut r_cmd),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+            process::exit(command_handler.variables.last_status);
+        }
         Err(_) => {
             return;
         }
     }
 
-    let parsed_command = parse::parse(&mut raw_command);
-    let command: ParsedCommand;
-    match parsed_command {
-        Ok(cmd) => command = cmd,
-        Err(e) => {
-            io_handler.stderr(format_args!("{}", e));
-            return;
+    // keep reading lines while the command is unfinished
+    let command_list: CommandList = loop {
+        match parse::parse(&raw_command) {
+            Ok(command_list) => break command_list,
+            Err(ShellError::Syntax(diagnostic)) if diagnostic.incomplete => {
+                let prompt = command_handler
+                    .variables
+                    .get("PS2")
+                    .unwrap_or_else(|| "> ".to_string());
+                match IOHandler::get_continuation_line(&prompt) {
+                    Ok(Some(line)) => {
+                        raw_command.push('\n');
+                        raw_command.push_str(&line);
+                    }
+                    _ => {
+                        io_handler.stderr(format_args!("{}", diagnostic));
+                        command_handler.variables.last_status = 2;
+                        return;
+                    }
+                }
+            }
+            Err(ShellError::NullInput) => return,
+            Err(e) => {
+                io_handler.stderr(format_args!("{}", e));
+                command_handler.variables.last_status = e.exit_code();
+                return;
+            }
         }
-    }
-    IOHandler::debug(format_args!("{:?}", command));
-    let c_command = command.clone();
-    if !command.stdout.is_empty() {
-        io_handler.set_stdout_file(
-            &mut c_command.stdout.clone(),
-            &c_command.stdout_mode.unwrap(),
-        );
-    }
+    };
+    IOHandler::debug(format_args!("{:?}", command_list));
 
-    if !command.stderr.is_empty() {
-        io_handler.set_stderr_file(
-            &mut c_command.stderr.clone(),
-            &c_command.stderr_mode.unwrap(),
-        );
-    }
+    command_handler.run_list(&command_list, io_handler);
+}
 
-    match command_handler.run(command, io_handler) {
-        Ok(_) => {}
-        Err(e) => io_handler.stderr(format_args!("{}", e)),
+/// Runs the script file named on the command line, with the arguments after it as the
+/// positional parameters. Each command runs before the next one is parsed, so a syntax error
+/// is reported, with the file name and line, only once the commands before it have run.
+fn run_script(command_handler: &mut CommandHandler, io_handler: &IOHandler, path: &str) -> i32 {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(_) => {
+            io_handler.stderr(format_args!(
+                "{}",
+                ShellError::FileNotExist(path.to_string())
+            ));
+            return 127;
+        }
+    };
+    command_handler.variables.arg0 = path.to_string();
+    command_handler.variables.positional.remove(0);
+    let mut script = parse::Script::new(&source);
+    let mut status = 0;
+    loop {
+        match script.next_command() {
+            Ok(Some(command_list)) => status = command_handler.run_list(&command_list, io_handler),
+            Ok(None) => return status,
+            Err(ShellError::Syntax(diagnostic)) => {
+                io_handler.stderr(format_args!("{}", diagnostic.in_file(path)));
+                return 2;
+            }
+            Err(e) => {
+                io_handler.stderr(format_args!("{}", e));
+                return e.exit_code();
+            }
+        }
     }
 }
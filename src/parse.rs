@@ -1,210 +1,864 @@
-use std::{cell::RefCell, collections::HashMap, env, fmt::Display, rc::Rc, str::FromStr};
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, HashMap},
+    env,
+    fmt::Display,
+    fs::File,
+    io, mem,
+    os::fd::OwnedFd,
+    rc::Rc,
+    str::FromStr,
+};
 
 use crate::{
-    command, error::ShellError, shellio::{IOHandler, OutMode}, utils::{execute_external, search_file_in_paths}
+    command,
+    error::ShellError,
+    expand,
+    glob::GlobOptions,
+    lexer::{Lexer, Token, TokenKind},
+    shellio::{IOHandler, InMode, OutMode},
+    utils::{
+        Process, execute_external, exit_code, is_running, run_forked, search_file_in_paths,
+        spawn_forked,
+    },
+    variable::Variables,
 };
 
 pub type Command = String;
 pub type Arg = String;
 pub type Args = Vec<String>;
 pub type EnvPath = Vec<String>;
-pub type Handler = fn(ParsedCommand, RunTimeEnvPath, &IOHandler) -> ShellResult;
+pub type Handler = fn(ParsedCommand, &mut CommandHandler, &IOHandler) -> ShellResult;
 pub type RunTimeEnvPath = Rc<RefCell<EnvPath>>;
 pub type ShellResult = Result<i32, ShellError>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct ParsedCommand {
     pub command: Command,
     pub args: Args,
-    pub stdout: String,
-    pub stderr: String,
-    pub stdout_mode: Option<OutMode>,
-    pub stderr_mode: Option<OutMode>
+    pub assignments: Vec<(String, String)>,
+    pub redirects: Vec<Redirect>,
+}
+
+/// A single redirection; a command's redirections are applied in source order.
+#[derive(Clone, Debug)]
+pub enum Redirect {
+    Input {
+        fd: i32,
+        path: String,
+        mode: InMode,
+    },
+    Output {
+        fd: i32,
+        path: String,
+        mode: OutMode,
+    },
+    Duplicate {
+        fd: i32,
+        target: i32,
+    },
+    Close {
+        fd: i32,
+    },
+    HereDoc {
+        fd: i32,
+        heredoc: HereDoc,
+    },
+    HereString {
+        fd: i32,
+        word: String,
+    },
+}
+
+/// A `<<` redirection; `body` is taken from the lines following the command.
+#[derive(Clone, Debug, Default)]
+pub struct HereDoc {
+    pub delimiter: String,
+    pub strip_tabs: bool,
+    pub expand: bool,
+    pub body: String,
+}
+
+/// One stage of a pipeline.
+#[derive(Clone, Debug)]
+pub enum ShellCommand {
+    Simple(ParsedCommand),
+    /// A compound command with the redirections written after it.
+    Compound(CompoundCommand, Vec<Redirect>),
+    /// `name() body`, which defines the function `name` when run.
+    FunctionDef(String, Rc<Function>),
 }
 
+/// A shell function: its body is run with the redirections on every call.
+#[derive(Debug)]
+pub struct Function {
+    pub body: CompoundCommand,
+    pub redirects: Vec<Redirect>,
+    /// The definition as written, shown by `type`.
+    pub source: String,
+}
+
+#[derive(Clone, Debug)]
+pub enum CompoundCommand {
+    /// `((expression))`
+    Arithmetic(String),
+    /// `{ list; }`, run in the current shell
+    BraceGroup(CommandList),
+    /// `( list )`, run in a forked copy of the shell
+    Subshell(CommandList),
+    /// `if list; then list; [elif list; then list;]... [else list;] fi`
+    If {
+        branches: Vec<(CommandList, CommandList)>,
+        otherwise: Option<CommandList>,
+    },
+    /// `while list; do list; done`, or `until` when `until` is set
+    While {
+        until: bool,
+        condition: CommandList,
+        body: CommandList,
+    },
+    /// `for name [in word...]; do list; done`; without `in` the positional parameters
+    For {
+        name: String,
+        words: Option<Vec<String>>,
+        body: CommandList,
+    },
+    /// `for ((init; condition; step)); do list; done`
+    ArithmeticFor {
+        init: String,
+        condition: String,
+        step: String,
+        body: CommandList,
+    },
+    /// `case word in pattern [| pattern]...) list ;; ... esac`
+    Case { word: String, items: Vec<CaseItem> },
+}
+
+/// One `pattern) list ;;` arm of a `case` command.
+#[derive(Clone, Debug)]
+pub struct CaseItem {
+    pub patterns: Vec<String>,
+    pub body: CommandList,
+    pub terminator: CaseTerminator,
+}
+
+/// What happens after a matching `case` arm has run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaseTerminator {
+    /// `;;`: the `case` is done
+    Break,
+    /// `;&`: the next arm's list runs as well, without testing its patterns
+    FallThrough,
+    /// `;;&`: the following arms are tested as usual
+    Continue,
+}
+
+/// Commands joined by `|`; `negated` is set by a leading `!`.
+#[derive(Clone, Debug, Default)]
+pub struct Pipeline {
+    pub negated: bool,
+    pub commands: Vec<ShellCommand>,
+}
+
+/// How a pipeline is joined to the one before it in a command list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Connector {
+    Seq,
+    And,
+    Or,
+}
+
+/// A pipeline in a command list together with the operator joining it to the previous one.
+#[derive(Clone, Debug)]
+pub struct ListItem {
+    pub connector: Connector,
+    pub pipeline: Pipeline,
+    pub background: bool,
+}
+
+pub type CommandList = Vec<ListItem>;
+
 #[derive(PartialEq)]
 pub enum ParseMode {
     SingleQuote,
     DoubleQuote,
+    /// `$'...'`, whose backslash escapes are decoded when the word is read
+    AnsiCQuote,
     None,
 }
 
-const SINGLE_QUOTE: char = '\'';
-const DOUBLE_QUOTE: char = '\"';
-const BACKSLASH: char = '\\';
-const WHITESPACE: char = ' ';
-const NEWLINE: char = '\n';
-const DOLLAR: char = '$';
-const BACKTICK: char = '`';
-const REDIRECT: char = '>';
-const UNIX_STDOUT_REDIRECT: char = '1';
-const UNIX_STDERR_REDIRECT: char = '2';
-
-pub fn parse(raw_command: &mut String) -> Result<ParsedCommand, ShellError> {
-    if raw_command.is_empty() {
-        return Err(ShellError::NullInput);
+const CLOSE_FD: &str = "-";
+const UNIX_STDIN_REDIRECT: i32 = 0;
+const UNIX_STDOUT_REDIRECT: i32 = 1;
+const UNIX_STDERR_REDIRECT: i32 = 2;
+const REDIRECT_OPERATORS: [&str; 12] = [
+    "<", "<>", "<&", "<<", "<<-", "<<<", ">", ">>", ">|", ">&", "&>", "&>>",
+];
+
+// reserved words that close a compound command, and so end the list before them
+const TERMINATORS: [&str; 8] = ["}", "then", "elif", "else", "fi", "do", "done", "esac"];
+
+/// Recursive-descent parser for the shell grammar:
+///
+/// ```text
+/// program   := linebreak list linebreak EOF
+/// list      := and_or ((';' | '&' | NEWLINE) linebreak and_or)* [';' | '&']
+/// and_or    := pipeline (('&&' | '||') linebreak pipeline)*
+/// pipeline  := ['!'] command ('|' linebreak command)*
+/// command   := simple_command | compound_command redirect* | function_def
+/// function_def := (name '(' ')' | 'function' name ['(' ')']) linebreak compound_command redirect*
+/// compound_command := '((' expression '))' | '{' list '}' | '(' list ')' | if_clause
+/// if_clause := 'if' list 'then' list ('elif' list 'then' list)* ['else' list] 'fi'
+/// while_clause := ('while' | 'until') list do_group
+/// for_clause := 'for' name [linebreak 'in' word*] sequential_sep do_group
+///             | 'for' '((' expr ';' expr ';' expr '))' [sequential_sep] do_group
+/// do_group  := 'do' list 'done'
+/// case_clause := 'case' word linebreak 'in' linebreak case_item* 'esac'
+/// case_item := ['('] word ('|' word)* ')' list [';;' | ';&' | ';;&'] linebreak
+/// ```
+struct Parser {
+    lexer: Lexer,
+}
+
+impl Parser {
+    fn parse_program(&mut self) -> Result<CommandList, ShellError> {
+        let list = self.parse_list()?;
+        let token = self.lexer.next_token()?;
+        match token.kind {
+            TokenKind::Eof => Ok(list),
+            _ => Err(self.unexpected(&token)),
+        }
     }
-    let mut tokens: Vec<String> = Vec::new();
-    let mut current_token = String::new();
-    let mut mode = ParseMode::None;
-    let mut stdout_redirected = false;
-    let mut stderr_redirected = false;
-    let mut chars_iter = raw_command.chars().peekable();
-    let mut redirect_stdout = String::new();
-    let mut redirect_stderr = String::new();
-    let mut stdout_redirect_mode :Option<OutMode> = None;
-    let mut stderr_redirect_mode :Option<OutMode> = None;
-    while let Some(ch) = chars_iter.next() {
-        match mode {
-            ParseMode::None => match ch {
-                SINGLE_QUOTE => mode = ParseMode::SingleQuote,
-                DOUBLE_QUOTE => mode = ParseMode::DoubleQuote,
-                BACKSLASH => {
-                    if let Some(next_ch) = chars_iter.next() {
-                        current_token.push(next_ch);
+
+    /// Parses the and-or lists on the next line that holds a command, up to and including
+    /// its newline; `None` at the end of the input.
+    fn parse_line(&mut self) -> Result<Option<CommandList>, ShellError> {
+        self.skip_newlines()?;
+        if self.lexer.peek_token()?.kind == TokenKind::Eof {
+            return Ok(None);
+        }
+        let mut list = CommandList::new();
+        loop {
+            if !self.at_command()? {
+                let token = self.lexer.next_token()?;
+                return Err(self.unexpected(&token));
+            }
+            self.parse_and_or(&mut list)?;
+            let token = self.lexer.next_token()?;
+            match token.kind {
+                TokenKind::Newline | TokenKind::Eof => return Ok(Some(list)),
+                TokenKind::Operator(";") => {}
+                TokenKind::Operator("&") => {
+                    if let Some(item) = list.last_mut() {
+                        item.background = true;
                     }
                 }
-                WHITESPACE => {
-                    if !current_token.is_empty() {
-                        if stdout_redirected {
-                            redirect_stdout = std::mem::take(&mut current_token);
-                            stdout_redirected = false;
-                        } else if stderr_redirected {
-                            redirect_stderr = std::mem::take(&mut current_token);
-                            stderr_redirected = false;
-                        } else {
-                            tokens.push(std::mem::take(&mut current_token));
-                        }
-                        current_token.clear();
-                    }
+                _ => return Err(self.unexpected(&token)),
+            }
+            match self.lexer.peek_token()?.kind {
+                TokenKind::Newline | TokenKind::Eof => {
+                    self.lexer.next_token()?;
+                    return Ok(Some(list));
                 }
-                REDIRECT =>  {
-                    stdout_redirected = true;
-                    if let Some(&next_ch) = chars_iter.peek() && next_ch == REDIRECT {
-                        stdout_redirect_mode = Some(OutMode::APPEND);
-                        chars_iter.next();
-                    } else {
-                        stdout_redirect_mode = Some(OutMode::WRITE);
-                    }
-                } 
-                UNIX_STDOUT_REDIRECT => {
-                    if let Some(&next_ch) = chars_iter.peek() && next_ch == REDIRECT {
-                        stdout_redirected = true;
-                        chars_iter.next();
-                        if let Some(&next2ch) = chars_iter.peek() && next2ch == REDIRECT {
-                            stdout_redirect_mode = Some(OutMode::APPEND);
-                            chars_iter.next();
-                        } else {
-                            stdout_redirect_mode = Some(OutMode::WRITE);
-                        }
-                    } else {
-                        current_token.push(ch);
+                _ => {}
+            }
+        }
+    }
+
+    /// An error for a token that cannot appear where it was found; running out of input
+    /// makes the error incomplete rather than final.
+    fn unexpected(&self, token: &Token) -> ShellError {
+        let message = format!("unexpected {}", token.describe());
+        match token.kind {
+            TokenKind::Eof => self.lexer.incomplete(message, token.start..token.end),
+            _ => self.lexer.error(message, token.start..token.end),
+        }
+    }
+
+    fn skip_newlines(&mut self) -> Result<(), ShellError> {
+        while self.lexer.peek_token()?.kind == TokenKind::Newline {
+            self.lexer.next_token()?;
+        }
+        Ok(())
+    }
+
+    /// Whether the next token can start a command rather than end the list.
+    fn at_command(&mut self) -> Result<bool, ShellError> {
+        let token = self.lexer.peek_token()?;
+        Ok(match &token.kind {
+            TokenKind::Word(word) => !TERMINATORS.contains(&word.as_str()),
+            TokenKind::IoNumber(_) => true,
+            TokenKind::Operator(op) => *op == "(" || REDIRECT_OPERATORS.contains(op),
+            TokenKind::Newline | TokenKind::Eof => false,
+        })
+    }
+
+    fn parse_list(&mut self) -> Result<CommandList, ShellError> {
+        let mut list = CommandList::new();
+        loop {
+            self.skip_newlines()?;
+            if !self.at_command()? {
+                return Ok(list);
+            }
+            self.parse_and_or(&mut list)?;
+            match self.lexer.peek_token()?.kind {
+                TokenKind::Operator(";") | TokenKind::Newline => {}
+                TokenKind::Operator("&") => {
+                    if let Some(item) = list.last_mut() {
+                        item.background = true;
                     }
                 }
-                 UNIX_STDERR_REDIRECT => {
-                    if let Some(&next_ch) = chars_iter.peek() && next_ch == REDIRECT {
-                        stderr_redirected = true;
-                        chars_iter.next();
-                        if let Some(&next2ch) = chars_iter.peek() && next2ch == REDIRECT {
-                            stderr_redirect_mode = Some(OutMode::APPEND);
-                            chars_iter.next();
-                        } else {
-                            stderr_redirect_mode = Some(OutMode::WRITE);
-                        }
-                    } else {
-                        current_token.push(ch);
-                    }
+                _ => return Ok(list),
+            }
+            self.lexer.next_token()?;
+        }
+    }
+
+    fn parse_and_or(&mut self, list: &mut CommandList) -> Result<(), ShellError> {
+        let mut connector = Connector::Seq;
+        loop {
+            list.push(ListItem {
+                connector,
+                pipeline: self.parse_pipeline()?,
+                background: false,
+            });
+            connector = match self.lexer.peek_token()?.kind {
+                TokenKind::Operator("&&") => Connector::And,
+                TokenKind::Operator("||") => Connector::Or,
+                _ => return Ok(()),
+            };
+            self.lexer.next_token()?;
+            self.skip_newlines()?;
+        }
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline, ShellError> {
+        let mut pipeline = Pipeline::default();
+        if self.lexer.peek_token()?.is_word("!") {
+            self.lexer.next_token()?;
+            pipeline.negated = true;
+        }
+        pipeline.commands.push(self.parse_command()?);
+        while self.lexer.peek_token()?.is_operator("|") {
+            self.lexer.next_token()?;
+            self.skip_newlines()?;
+            pipeline.commands.push(self.parse_command()?);
+        }
+        Ok(pipeline)
+    }
+
+    fn parse_command(&mut self) -> Result<ShellCommand, ShellError> {
+        let compound = if let Some(expression) = self.lexer.read_arithmetic()? {
+            CompoundCommand::Arithmetic(expression)
+        } else if self.lexer.peek_token()?.is_operator("(") {
+            CompoundCommand::Subshell(self.parse_group(")")?)
+        } else if self.lexer.peek_token()?.is_word("{") {
+            CompoundCommand::BraceGroup(self.parse_group("}")?)
+        } else if self.lexer.peek_token()?.is_word("if") {
+            self.parse_if()?
+        } else if self.lexer.peek_token()?.is_word("while")
+            || self.lexer.peek_token()?.is_word("until")
+        {
+            let until = self.lexer.next_token()?.is_word("until");
+            let condition = self.parse_compound_list()?;
+            CompoundCommand::While {
+                until,
+                condition,
+                body: self.parse_do_group()?,
+            }
+        } else if self.lexer.peek_token()?.is_word("for") {
+            self.parse_for()?
+        } else if self.lexer.peek_token()?.is_word("case") {
+            self.parse_case()?
+        } else if self.lexer.peek_token()?.is_word("function") {
+            let start = self.lexer.next_token()?.start;
+            let name = self.lexer.next_token()?;
+            if self.lexer.peek_token()?.is_operator("(") {
+                self.lexer.next_token()?;
+                self.expect_operator(")")?;
+            }
+            return self.parse_function(name, start);
+        } else {
+            let first = self.lexer.peek_token()?.clone();
+            let parsed_command = self.parse_simple_command()?;
+            // `name (` can only start a function definition
+            if parsed_command.args.is_empty()
+                && parsed_command.assignments.is_empty()
+                && parsed_command.redirects.is_empty()
+                && self.lexer.peek_token()?.is_operator("(")
+            {
+                self.lexer.next_token()?;
+                self.expect_operator(")")?;
+                let start = first.start;
+                return self.parse_function(first, start);
+            }
+            return Ok(ShellCommand::Simple(parsed_command));
+        };
+        Ok(ShellCommand::Compound(compound, self.parse_redirects()?))
+    }
+
+    /// The list between an opening `(` or `{` and `close`.
+    fn parse_group(&mut self, close: &str) -> Result<CommandList, ShellError> {
+        self.lexer.next_token()?;
+        let list = self.parse_compound_list()?;
+        let token = self.lexer.next_token()?;
+        if !(token.is_operator(close) || token.is_word(close)) {
+            return Err(self.unexpected(&token));
+        }
+        Ok(list)
+    }
+
+    /// The list inside a compound command, which must hold at least one command.
+    fn parse_compound_list(&mut self) -> Result<CommandList, ShellError> {
+        let list = self.parse_list()?;
+        if list.is_empty() {
+            let token = self.lexer.next_token()?;
+            return Err(self.unexpected(&token));
+        }
+        Ok(list)
+    }
+
+    /// Consumes the reserved word `word`, which must come next.
+    fn expect_word(&mut self, word: &str) -> Result<(), ShellError> {
+        let token = self.lexer.next_token()?;
+        if !token.is_word(word) {
+            return Err(self.unexpected(&token));
+        }
+        Ok(())
+    }
+
+    fn expect_operator(&mut self, op: &str) -> Result<(), ShellError> {
+        let token = self.lexer.next_token()?;
+        if !token.is_operator(op) {
+            return Err(self.unexpected(&token));
+        }
+        Ok(())
+    }
+
+    /// The body of a function definition named by `name`, which starts at `start`.
+    fn parse_function(&mut self, name: Token, start: usize) -> Result<ShellCommand, ShellError> {
+        let name = match &name.kind {
+            TokenKind::Word(word) if Self::is_function_name(word) => word.clone(),
+            TokenKind::Word(word) => {
+                return Err(self.lexer.error(
+                    format!("'{}' is not a valid function name", word),
+                    name.start..name.end,
+                ));
+            }
+            _ => return Err(self.unexpected(&name)),
+        };
+        self.skip_newlines()?;
+        let token = self.lexer.peek_token()?.clone();
+        let ShellCommand::Compound(body, redirects) = self.parse_command()? else {
+            return Err(self.unexpected(&token));
+        };
+        let function = Function {
+            body,
+            redirects,
+            source: self.lexer.text_since(start)?,
+        };
+        Ok(ShellCommand::FunctionDef(name, Rc::new(function)))
+    }
+
+    /// Whether `name` can name a function: a word of letters, digits and `_-.:` that is not a
+    /// number.
+    fn is_function_name(name: &str) -> bool {
+        !name.chars().all(|ch| ch.is_ascii_digit())
+            && name
+                .chars()
+                .all(|ch| ch.is_ascii_alphanumeric() || "_-.:".contains(ch))
+    }
+
+    fn parse_do_group(&mut self) -> Result<CommandList, ShellError> {
+        self.expect_word("do")?;
+        let body = self.parse_compound_list()?;
+        self.expect_word("done")?;
+        Ok(body)
+    }
+
+    /// Skips a `;` or newlines before `do`.
+    fn skip_separator(&mut self) -> Result<(), ShellError> {
+        if self.lexer.peek_token()?.is_operator(";") {
+            self.lexer.next_token()?;
+        }
+        self.skip_newlines()
+    }
+
+    fn parse_for(&mut self) -> Result<CompoundCommand, ShellError> {
+        let for_token = self.lexer.next_token()?;
+        if let Some(expression) = self.lexer.read_arithmetic()? {
+            let parts: Vec<&str> = expression.split(';').collect();
+            let [init, condition, step] = parts[..] else {
+                return Err(self.lexer.error(
+                    "expected three expressions in 'for ((...))'".to_string(),
+                    for_token.start..for_token.end,
+                ));
+            };
+            let (init, condition, step) =
+                (init.to_string(), condition.to_string(), step.to_string());
+            self.skip_separator()?;
+            return Ok(CompoundCommand::ArithmeticFor {
+                init,
+                condition,
+                step,
+                body: self.parse_do_group()?,
+            });
+        }
+        let token = self.lexer.next_token()?;
+        let name = match &token.kind {
+            TokenKind::Word(name) if Variables::is_name(name) => name.clone(),
+            _ => return Err(self.unexpected(&token)),
+        };
+        self.skip_newlines()?;
+        let mut words = None;
+        if self.lexer.peek_token()?.is_word("in") {
+            self.lexer.next_token()?;
+            let mut list = Vec::new();
+            while let TokenKind::Word(word) = &self.lexer.peek_token()?.kind {
+                list.push(word.clone());
+                self.lexer.next_token()?;
+            }
+            let token = self.lexer.next_token()?;
+            if !(token.is_operator(";") || token.kind == TokenKind::Newline) {
+                return Err(self.unexpected(&token));
+            }
+            words = Some(list);
+        }
+        self.skip_separator()?;
+        Ok(CompoundCommand::For {
+            name,
+            words,
+            body: self.parse_do_group()?,
+        })
+    }
+
+    fn parse_case(&mut self) -> Result<CompoundCommand, ShellError> {
+        self.lexer.next_token()?;
+        let token = self.lexer.next_token()?;
+        let TokenKind::Word(word) = token.kind.clone() else {
+            return Err(self.unexpected(&token));
+        };
+        self.skip_newlines()?;
+        self.expect_word("in")?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_newlines()?;
+            if self.lexer.peek_token()?.is_word("esac") {
+                self.lexer.next_token()?;
+                return Ok(CompoundCommand::Case { word, items });
+            }
+            if self.lexer.peek_token()?.is_operator("(") {
+                self.lexer.next_token()?;
+            }
+            let mut patterns = Vec::new();
+            loop {
+                let token = self.lexer.next_token()?;
+                let TokenKind::Word(pattern) = token.kind.clone() else {
+                    return Err(self.unexpected(&token));
+                };
+                patterns.push(pattern);
+                let token = self.lexer.next_token()?;
+                if token.is_operator(")") {
+                    break;
+                }
+                if !token.is_operator("|") {
+                    return Err(self.unexpected(&token));
+                }
+            }
+            let body = self.parse_list()?;
+            let token = self.lexer.peek_token()?;
+            let terminator = match token.kind {
+                TokenKind::Operator(";;") => CaseTerminator::Break,
+                TokenKind::Operator(";&") => CaseTerminator::FallThrough,
+                TokenKind::Operator(";;&") => CaseTerminator::Continue,
+                // the last arm may go without one
+                _ if token.is_word("esac") => {
+                    items.push(CaseItem {
+                        patterns,
+                        body,
+                        terminator: CaseTerminator::Break,
+                    });
+                    continue;
                 }
                 _ => {
-                    if ch == NEWLINE {
-                        break;
-                    }
-                    current_token.push(ch);
+                    let token = token.clone();
+                    return Err(self.unexpected(&token));
+                }
+            };
+            self.lexer.next_token()?;
+            items.push(CaseItem {
+                patterns,
+                body,
+                terminator,
+            });
+        }
+    }
+
+    fn parse_if(&mut self) -> Result<CompoundCommand, ShellError> {
+        self.lexer.next_token()?;
+        let mut branches = Vec::new();
+        loop {
+            let condition = self.parse_compound_list()?;
+            self.expect_word("then")?;
+            branches.push((condition, self.parse_compound_list()?));
+            let token = self.lexer.next_token()?;
+            if token.is_word("elif") {
+                continue;
+            }
+            let otherwise = if token.is_word("else") {
+                let otherwise = self.parse_compound_list()?;
+                self.expect_word("fi")?;
+                Some(otherwise)
+            } else if token.is_word("fi") {
+                None
+            } else {
+                return Err(self.unexpected(&token));
+            };
+            return Ok(CompoundCommand::If {
+                branches,
+                otherwise,
+            });
+        }
+    }
+
+    /// The redirections following a compound command.
+    fn parse_redirects(&mut self) -> Result<Vec<Redirect>, ShellError> {
+        let mut redirects = Vec::new();
+        while self.parse_redirect(&mut redirects)? {}
+        Ok(redirects)
+    }
+
+    fn parse_simple_command(&mut self) -> Result<ParsedCommand, ShellError> {
+        let mut parsed_command = ParsedCommand::default();
+        let mut words: Vec<String> = Vec::new();
+        loop {
+            if self.parse_redirect(&mut parsed_command.redirects)? {
+                continue;
+            }
+            let TokenKind::Word(word) = &self.lexer.peek_token()?.kind else {
+                break;
+            };
+            match word.split_once('=') {
+                Some((name, value)) if words.is_empty() && Variables::is_name(name) => {
+                    parsed_command
+                        .assignments
+                        .push((name.to_string(), value.to_string()));
                 }
+                _ => words.push(word.clone()),
+            }
+            self.lexer.next_token()?;
+        }
+        match words.split_first() {
+            Some((command, args)) => {
+                parsed_command.command = command.clone();
+                parsed_command.args = args.to_vec();
+            }
+            // a bare `NAME=value` only sets shell variables, and `> file` only opens it
+            None if !parsed_command.assignments.is_empty()
+                || !parsed_command.redirects.is_empty() => {}
+            None => {
+                let token = self.lexer.next_token()?;
+                return Err(self.unexpected(&token));
+            }
+        }
+        Ok(parsed_command)
+    }
+
+    /// Parses a redirection if one comes next, with an optional file descriptor in front;
+    /// returns whether there was one.
+    fn parse_redirect(&mut self, redirects: &mut Vec<Redirect>) -> Result<bool, ShellError> {
+        let fd = match self.lexer.peek_token()?.kind {
+            TokenKind::IoNumber(fd) => {
+                self.lexer.next_token()?;
+                Some(fd)
+            }
+            TokenKind::Operator(op) if REDIRECT_OPERATORS.contains(&op) => None,
+            _ => return Ok(false),
+        };
+        let token = self.lexer.next_token()?;
+        let TokenKind::Operator(op) = token.kind else {
+            return Err(self.unexpected(&token));
+        };
+        let token = self.lexer.next_token()?;
+        let TokenKind::Word(word) = token.kind.clone() else {
+            return Err(self.unexpected(&token));
+        };
+        let input_fd = fd.unwrap_or(UNIX_STDIN_REDIRECT);
+        let output_fd = fd.unwrap_or(UNIX_STDOUT_REDIRECT);
+        let redirect = match op {
+            "<" | "<>" => Redirect::Input {
+                fd: input_fd,
+                path: word,
+                mode: if op == "<" {
+                    InMode::READ
+                } else {
+                    InMode::READWRITE
+                },
             },
-            ParseMode::SingleQuote => match ch {
-                SINGLE_QUOTE => mode = ParseMode::None,
-                _ => current_token.push(ch),
+            ">" | ">|" | ">>" => Redirect::Output {
+                fd: output_fd,
+                path: word,
+                mode: if op == ">>" {
+                    OutMode::APPEND
+                } else {
+                    OutMode::WRITE
+                },
             },
-            ParseMode::DoubleQuote => match ch {
-                DOUBLE_QUOTE => mode = ParseMode::None,
-                BACKSLASH => {
-                    if let Some(&next_ch) = chars_iter.peek() {
-                        if matches!(
-                            next_ch,
-                            BACKSLASH | DOUBLE_QUOTE | DOLLAR | BACKTICK | NEWLINE
-                        ) {
-                            chars_iter.next();
-                            if next_ch != NEWLINE {
-                                current_token.push(next_ch);
-                            }
-                        } else {
-                            current_token.push(ch);
-                        }
-                    }
+            "<&" | ">&" => {
+                let fd = if op == "<&" { input_fd } else { output_fd };
+                if word == CLOSE_FD {
+                    Redirect::Close { fd }
+                } else if let Ok(target) = word.parse::<i32>() {
+                    Redirect::Duplicate { fd, target }
+                } else if op == ">&" && fd == UNIX_STDOUT_REDIRECT {
+                    // `>&word` is the old spelling of `&>word`
+                    Self::push_combined(redirects, word, OutMode::WRITE);
+                    return Ok(true);
+                } else {
+                    return Err(self.lexer.error(
+                        format!("expected a file descriptor or '-' after '{}'", op),
+                        token.start..token.end,
+                    ));
+                }
+            }
+            "&>" | "&>>" => {
+                let mode = if op == "&>>" {
+                    OutMode::APPEND
+                } else {
+                    OutMode::WRITE
+                };
+                Self::push_combined(redirects, word, mode);
+                return Ok(true);
+            }
+            "<<" | "<<-" => {
+                let delimiter = expand::remove_quotes(&word);
+                let strip_tabs = op == "<<-";
+                let (body, complete) = self.lexer.read_heredoc_body(&delimiter, strip_tabs);
+                if !complete {
+                    return Err(self.lexer.incomplete(
+                        format!("here-document wanted '{}' before end of input", delimiter),
+                        token.start..token.end,
+                    ));
+                }
+                Redirect::HereDoc {
+                    fd: input_fd,
+                    heredoc: HereDoc {
+                        delimiter,
+                        strip_tabs,
+                        expand: !word.contains(['\'', '"', '\\']),
+                        body,
+                    },
                 }
-                _ => current_token.push(ch),
+            }
+            _ => Redirect::HereString { fd: input_fd, word },
+        };
+        redirects.push(redirect);
+        Ok(true)
+    }
+
+    /// `&>path`: stdout to `path` and stderr along with it.
+    fn push_combined(redirects: &mut Vec<Redirect>, path: String, mode: OutMode) {
+        redirects.push(Redirect::Output {
+            fd: UNIX_STDOUT_REDIRECT,
+            path,
+            mode,
+        });
+        redirects.push(Redirect::Duplicate {
+            fd: UNIX_STDERR_REDIRECT,
+            target: UNIX_STDOUT_REDIRECT,
+        });
+    }
+}
+
+/// A script read one line of commands at a time, so that each can run before the rest of
+/// the script is parsed.
+pub struct Script {
+    parser: Parser,
+}
+
+impl Script {
+    pub fn new(source: &str) -> Script {
+        Script {
+            parser: Parser {
+                lexer: Lexer::new(source),
             },
         }
     }
 
-    if !current_token.is_empty() {
-        if stdout_redirected {
-            redirect_stdout = std::mem::take(&mut current_token);
-        } else if stderr_redirected {
-            redirect_stderr = std::mem::take(&mut current_token);
-        } else {
-            tokens.push(std::mem::take(&mut current_token));
-        }
+    /// The commands on the next line, with those it continues onto; `None` once the script
+    /// is done. A syntax error is only found when the line holding it is reached.
+    pub fn next_command(&mut self) -> Result<Option<CommandList>, ShellError> {
+        self.parser.parse_line()
+    }
+}
+
+pub fn parse(raw_command: &str) -> Result<CommandList, ShellError> {
+    if raw_command.trim().is_empty() {
+        return Err(ShellError::NullInput);
     }
-    Ok(ParsedCommand {
-        command: tokens[0].clone(),
-        args: tokens[1..].to_vec(),
-        stdout: redirect_stdout,
-        stderr: redirect_stderr,
-        stdout_mode: stdout_redirect_mode,
-        stderr_mode: stderr_redirect_mode,
-    })
+    let mut parser = Parser {
+        lexer: Lexer::new(raw_command),
+    };
+    parser.parse_program()
 }
 
-impl<'a> PartialEq<&str> for ParsedCommand {
+impl PartialEq<&str> for ParsedCommand {
     fn eq(&self, target: &&str) -> bool {
         self.command == *target
     }
 }
 
-impl<'a> PartialEq<ParsedCommand> for &'a str {
+impl PartialEq<ParsedCommand> for &str {
     fn eq(&self, parsed_command: &ParsedCommand) -> bool {
         *self == parsed_command.command
     }
 }
 
-impl<'a> AsRef<str> for ParsedCommand {
+impl AsRef<str> for ParsedCommand {
     fn as_ref(&self) -> &str {
         &self.command
     }
 }
 
 pub fn get_env_path() -> EnvPath {
-    let path_string = env::var("PATH").unwrap_or_default();
+    split_path(&env::var("PATH").unwrap_or_default())
+}
+
+pub fn split_path(path_string: &str) -> EnvPath {
     let env_path: EnvPath = path_string
         .split(if cfg!(windows) { ';' } else { ':' })
         .filter(|&p| !p.is_empty() && p != "$PATH")
-        .map(|p| String::from(p))
+        .map(String::from)
         .collect();
 
     env_path
 }
 
-pub fn get_env_home<'a>() -> String {
-    env::var("HOME").unwrap_or_default()
-}
-
 pub struct CommandHandler {
     built_in_command: HashMap<BuiltIn, Handler>,
+    pub functions: HashMap<String, Rc<Function>>,
     local_path: EnvPath,
     temp_path: EnvPath,
     runtime_path: RunTimeEnvPath,
+    /// Background processes not yet known to have finished.
+    jobs: Vec<libc::pid_t>,
+    pub variables: Variables,
+    pub glob_options: GlobOptions,
+    // status of the last `$(...)` run while expanding the current command
+    substitution_status: Option<i32>,
+    /// How many loops are running around the current command.
+    pub loop_depth: usize,
+    /// A `break` or `continue` on its way out to the loop it targets.
+    pub loop_control: Option<LoopControl>,
+}
+
+/// Set by `break n` and `continue n`, where `n` counts the enclosing loops still to leave,
+/// and by `return`, which leaves every loop in the function.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopControl {
+    Break(usize),
+    Continue(usize),
+    Return(i32),
 }
 
 #[derive(PartialEq, Debug, Clone, Copy, Hash, Eq)]
@@ -214,6 +868,14 @@ pub enum BuiltIn {
     CD,
     PWD,
     TYPE,
+    EXPORT,
+    UNSET,
+    LET,
+    SHOPT,
+    BREAK,
+    CONTINUE,
+    LOCAL,
+    RETURN,
 }
 
 impl FromStr for BuiltIn {
@@ -225,6 +887,14 @@ impl FromStr for BuiltIn {
             "cd" => Ok(BuiltIn::CD),
             "pwd" => Ok(BuiltIn::PWD),
             "type" => Ok(BuiltIn::TYPE),
+            "export" => Ok(BuiltIn::EXPORT),
+            "unset" => Ok(BuiltIn::UNSET),
+            "let" => Ok(BuiltIn::LET),
+            "shopt" => Ok(BuiltIn::SHOPT),
+            "break" => Ok(BuiltIn::BREAK),
+            "continue" => Ok(BuiltIn::CONTINUE),
+            "local" => Ok(BuiltIn::LOCAL),
+            "return" => Ok(BuiltIn::RETURN),
             _ => Err(ShellError::NotABuiltinCommand),
         }
     }
@@ -238,13 +908,26 @@ impl Display for BuiltIn {
     }
 }
 
+impl Default for CommandHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl CommandHandler {
     pub fn new() -> CommandHandler {
         let mut command_handler = CommandHandler {
             built_in_command: HashMap::new(),
+            functions: HashMap::new(),
             local_path: get_env_path(),
             temp_path: Vec::new(),
             runtime_path: Rc::new(RefCell::new(Vec::new())),
+            jobs: Vec::new(),
+            variables: Variables::new(),
+            glob_options: GlobOptions::default(),
+            substitution_status: None,
+            loop_depth: 0,
+            loop_control: None,
         };
 
         // register command
@@ -253,6 +936,14 @@ impl CommandHandler {
         command_handler.register(BuiltIn::CD, command::cd);
         command_handler.register(BuiltIn::PWD, command::pwd);
         command_handler.register(BuiltIn::TYPE, command::_type);
+        command_handler.register(BuiltIn::EXPORT, command::export);
+        command_handler.register(BuiltIn::UNSET, command::unset);
+        command_handler.register(BuiltIn::LET, command::_let);
+        command_handler.register(BuiltIn::SHOPT, command::shopt);
+        command_handler.register(BuiltIn::BREAK, command::_break);
+        command_handler.register(BuiltIn::CONTINUE, command::_continue);
+        command_handler.register(BuiltIn::LOCAL, command::local);
+        command_handler.register(BuiltIn::RETURN, command::_return);
 
         command_handler
     }
@@ -261,7 +952,38 @@ impl CommandHandler {
         self.built_in_command.insert(command, handler);
     }
 
-    fn get_runtime_path(&self) -> RunTimeEnvPath {
+    /// Sets a shell variable, keeping the command search path in step with `PATH`.
+    pub fn set_var(&mut self, name: &str, value: &str) {
+        self.variables.set(name, value);
+        if name == "PATH" {
+            self.update_search_path();
+        }
+    }
+
+    pub fn unset_var(&mut self, name: &str) {
+        self.variables.unset(name);
+        if name == "PATH" {
+            self.update_search_path();
+        }
+    }
+
+    fn update_search_path(&mut self) {
+        self.local_path = self
+            .variables
+            .get("PATH")
+            .map(|path| split_path(&path))
+            .unwrap_or_default();
+        self.runtime_path.borrow_mut().clear();
+    }
+
+    /// The environment for a child: exported variables plus the command's own assignments.
+    fn child_env(&self, parsed_command: &ParsedCommand) -> BTreeMap<String, String> {
+        let mut env = self.variables.environment();
+        env.extend(parsed_command.assignments.iter().cloned());
+        env
+    }
+
+    pub fn get_runtime_path(&self) -> RunTimeEnvPath {
         if self.runtime_path.borrow().is_empty() {
             let mut runtime_path = self.runtime_path.borrow_mut();
             *runtime_path = self
@@ -275,23 +997,811 @@ impl CommandHandler {
         self.runtime_path.clone()
     }
 
-    fn run_built_in_command(&self, command: BuiltIn, parsed_command: ParsedCommand, io_handler: &IOHandler) -> ShellResult {
-        self.built_in_command.get(&command).unwrap()(parsed_command, self.get_runtime_path(), io_handler)
+    fn run_built_in_command(
+        &mut self,
+        command: BuiltIn,
+        parsed_command: ParsedCommand,
+        io_handler: &IOHandler,
+    ) -> ShellResult {
+        let handler = *self.built_in_command.get(&command).unwrap();
+        handler(parsed_command, self, io_handler)
+    }
+
+    /// Runs a program found on the search path, or on the `PATH` assigned in front of the
+    /// command when there is one.
+    fn run_external_command(
+        &self,
+        parsed_command: ParsedCommand,
+        io_handler: &IOHandler,
+    ) -> ShellResult {
+        let paths = match parsed_command
+            .assignments
+            .iter()
+            .rev()
+            .find(|(name, _)| name == "PATH")
+        {
+            Some((_, path)) => Rc::new(RefCell::new(split_path(path))),
+            None => self.get_runtime_path(),
+        };
+        match search_file_in_paths(&parsed_command.command, paths) {
+            Some(_) => execute_external(
+                &parsed_command.command,
+                parsed_command.args.clone(),
+                &self.child_env(&parsed_command),
+                io_handler,
+            ),
+            None => Err(ShellError::CommandNotFound(parsed_command.command)),
+        }
+    }
+
+    pub fn set_redirects(
+        redirects: &[Redirect],
+        io_handler: &mut IOHandler,
+    ) -> Result<(), ShellError> {
+        for redirect in redirects {
+            let (fd, path, result) = match redirect {
+                Redirect::Input { fd, path, mode } => (
+                    *fd,
+                    path.as_str(),
+                    match fd {
+                        0 => io_handler.set_stdin_file(&mut path.clone(), mode),
+                        _ => Err(io::ErrorKind::InvalidInput.into()),
+                    },
+                ),
+                Redirect::Output { fd, path, mode } => (
+                    *fd,
+                    path.as_str(),
+                    match fd {
+                        1 => io_handler.set_stdout_file(&mut path.clone(), mode),
+                        2 => io_handler.set_stderr_file(&mut path.clone(), mode),
+                        _ => Err(io::ErrorKind::InvalidInput.into()),
+                    },
+                ),
+                Redirect::Duplicate { fd, target } => (
+                    if (0..=2).contains(fd) { *target } else { *fd },
+                    "",
+                    io_handler.duplicate(*fd, *target),
+                ),
+                Redirect::Close { fd } => (*fd, "", io_handler.close(*fd)),
+                Redirect::HereDoc { fd, heredoc } => (
+                    *fd,
+                    "",
+                    Self::set_stdin_buffer(*fd, heredoc.body.clone(), io_handler),
+                ),
+                Redirect::HereString { fd, word } => (
+                    *fd,
+                    "",
+                    Self::set_stdin_buffer(*fd, format!("{}\n", word), io_handler),
+                ),
+            };
+            result.map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => ShellError::FileNotExist(path.to_string()),
+                io::ErrorKind::InvalidInput => ShellError::BadFileDescriptor(fd),
+                _ => ShellError::Io(e),
+            })?;
+        }
+        Ok(())
+    }
+
+    fn set_stdin_buffer(fd: i32, buffer: String, io_handler: &mut IOHandler) -> io::Result<()> {
+        if fd != 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        io_handler.set_stdin_buffer(buffer);
+        Ok(())
+    }
+
+    pub fn expand_command(
+        &mut self,
+        parsed_command: &ParsedCommand,
+    ) -> Result<ParsedCommand, ShellError> {
+        self.substitution_status = None;
+        let mut words: Vec<String> = Vec::new();
+        for word in std::iter::once(&parsed_command.command).chain(parsed_command.args.iter()) {
+            words.extend(expand::expand_word(self, word)?);
+        }
+        let redirects = self.expand_redirects(&parsed_command.redirects)?;
+        let mut assignments: Vec<(String, String)> = Vec::new();
+        for (name, value) in &parsed_command.assignments {
+            assignments.push((name.clone(), expand::expand_assignment(self, value)?));
+        }
+        let mut words = words.into_iter();
+        Ok(ParsedCommand {
+            command: words.next().unwrap_or_default(),
+            args: words.collect(),
+            assignments,
+            redirects,
+        })
+    }
+
+    fn expand_redirects(&mut self, redirects: &[Redirect]) -> Result<Vec<Redirect>, ShellError> {
+        let mut expanded: Vec<Redirect> = Vec::new();
+        for redirect in redirects {
+            expanded.push(match redirect {
+                Redirect::Input { fd, path, mode } => Redirect::Input {
+                    fd: *fd,
+                    path: self.expand_redirect_target(path)?,
+                    mode: mode.clone(),
+                },
+                Redirect::Output { fd, path, mode } => Redirect::Output {
+                    fd: *fd,
+                    path: self.expand_redirect_target(path)?,
+                    mode: mode.clone(),
+                },
+                Redirect::HereDoc { fd, heredoc } => {
+                    let mut heredoc = heredoc.clone();
+                    if heredoc.expand {
+                        heredoc.body = expand::expand_heredoc(self, &heredoc.body)?;
+                    }
+                    Redirect::HereDoc { fd: *fd, heredoc }
+                }
+                Redirect::HereString { fd, word } => Redirect::HereString {
+                    fd: *fd,
+                    word: expand::expand_single(self, word)?,
+                },
+                redirect => redirect.clone(),
+            });
+        }
+        Ok(expanded)
+    }
+
+    fn expand_redirect_target(&mut self, word: &str) -> Result<String, ShellError> {
+        let mut fields = expand::expand_word(self, word)?;
+        if fields.len() != 1 {
+            return Err(ShellError::AmbiguousRedirect(word.to_string()));
+        }
+        Ok(fields.remove(0))
+    }
+
+    /// Runs an and-or list; every pipeline gets a fresh copy of `io_handler`'s streams.
+    pub fn run_list(&mut self, command_list: &CommandList, io_handler: &IOHandler) -> i32 {
+        let mut status = 0;
+        for item in command_list {
+            match item.connector {
+                Connector::And if status != 0 => continue,
+                Connector::Or if status == 0 => continue,
+                _ => {}
+            }
+            status = match io_handler.try_clone() {
+                Ok(mut item_io) => {
+                    self.exec_pipeline(&item.pipeline, &mut item_io, item.background)
+                }
+                Err(e) => {
+                    io_handler.stderr(format_args!("{}", e));
+                    1
+                }
+            };
+            self.variables.last_status = status;
+            // `break` and `continue` skip the rest of the list on the way to their loop
+            if self.loop_control.is_some() {
+                break;
+            }
+        }
+        status
+    }
+
+    fn exec_pipeline(
+        &mut self,
+        pipeline: &Pipeline,
+        io_handler: &mut IOHandler,
+        background: bool,
+    ) -> i32 {
+        let result = if pipeline.commands.len() > 1 || background {
+            self.run_pipeline(&pipeline.commands, io_handler, background)
+        } else {
+            self.exec_command(&pipeline.commands[0], io_handler)
+        };
+        let status = match result {
+            Ok(status) => status,
+            Err(e) => {
+                io_handler.stderr(format_args!("{}", e));
+                e.exit_code()
+            }
+        };
+        if pipeline.negated {
+            (status == 0) as i32
+        } else {
+            status
+        }
+    }
+
+    /// Expands and runs one command in the current shell.
+    fn exec_command(&mut self, command: &ShellCommand, io_handler: &mut IOHandler) -> ShellResult {
+        match command {
+            ShellCommand::Simple(parsed_command) => {
+                let parsed_command = self.expand_command(parsed_command)?;
+                CommandHandler::set_redirects(&parsed_command.redirects, io_handler)?;
+                self.run(parsed_command, io_handler)
+            }
+            ShellCommand::Compound(compound, redirects) => {
+                self.exec_compound(compound, redirects, io_handler)
+            }
+            ShellCommand::FunctionDef(name, function) => {
+                self.functions.insert(name.clone(), Rc::clone(function));
+                Ok(0)
+            }
+        }
+    }
+
+    /// Runs a compound command in the current shell with its redirections applied.
+    fn exec_compound(
+        &mut self,
+        compound: &CompoundCommand,
+        redirects: &[Redirect],
+        io_handler: &mut IOHandler,
+    ) -> ShellResult {
+        self.substitution_status = None;
+        let redirects = self.expand_redirects(redirects)?;
+        CommandHandler::set_redirects(&redirects, io_handler)?;
+        self.run_compound(compound, io_handler)
+    }
+
+    /// Runs a function with the command's arguments as its positional parameters. The
+    /// assignments in front of the call are local to it, like those made by `local`.
+    fn call_function(
+        &mut self,
+        function: Rc<Function>,
+        parsed_command: ParsedCommand,
+        io_handler: &mut IOHandler,
+    ) -> ShellResult {
+        let positional = mem::replace(&mut self.variables.positional, parsed_command.args);
+        // loops around the call cannot be left from inside the function
+        let loop_depth = mem::take(&mut self.loop_depth);
+        self.variables.push_scope();
+        for (name, value) in &parsed_command.assignments {
+            self.variables.make_local(name);
+            self.set_var(name, value);
+        }
+        let mut result = self.exec_compound(&function.body, &function.redirects, io_handler);
+        if let Some(LoopControl::Return(status)) = self.loop_control.take() {
+            result = Ok(status);
+        }
+        for name in self.variables.pop_scope() {
+            if name == "PATH" {
+                self.update_search_path();
+            }
+        }
+        self.loop_depth = loop_depth;
+        self.variables.positional = positional;
+        result
+    }
+
+    fn run_compound(
+        &mut self,
+        compound: &CompoundCommand,
+        io_handler: &mut IOHandler,
+    ) -> ShellResult {
+        match compound {
+            CompoundCommand::Arithmetic(expression) => {
+                let value = expand::expand_arithmetic(self, expression)?;
+                Ok((value == 0) as i32)
+            }
+            CompoundCommand::BraceGroup(list) => Ok(self.run_list(list, io_handler)),
+            CompoundCommand::Subshell(list) => {
+                run_forked(io_handler, |child_io| self.run_list(list, child_io))
+            }
+            CompoundCommand::If {
+                branches,
+                otherwise,
+            } => {
+                for (condition, body) in branches {
+                    let status = self.run_list(condition, io_handler);
+                    if self.loop_control.is_some() {
+                        return Ok(status);
+                    }
+                    if status == 0 {
+                        return Ok(self.run_list(body, io_handler));
+                    }
+                }
+                Ok(otherwise
+                    .as_ref()
+                    .map_or(0, |otherwise| self.run_list(otherwise, io_handler)))
+            }
+            CompoundCommand::While {
+                until,
+                condition,
+                body,
+            } => self.run_loop(|handler| {
+                let mut status = 0;
+                loop {
+                    let condition_status = handler.run_list(condition, io_handler);
+                    if handler.loop_control.is_some() {
+                        if handler.end_iteration() {
+                            break;
+                        }
+                        continue;
+                    }
+                    if (condition_status == 0) == *until {
+                        break;
+                    }
+                    status = handler.run_list(body, io_handler);
+                    if handler.end_iteration() {
+                        break;
+                    }
+                }
+                Ok(status)
+            }),
+            CompoundCommand::For { name, words, body } => {
+                let values = match words {
+                    Some(words) => {
+                        let mut values = Vec::new();
+                        for word in words {
+                            values.extend(expand::expand_word(self, word)?);
+                        }
+                        values
+                    }
+                    None => self.variables.positional.clone(),
+                };
+                self.run_loop(|handler| {
+                    let mut status = 0;
+                    for value in values {
+                        handler.set_var(name, &value);
+                        status = handler.run_list(body, io_handler);
+                        if handler.end_iteration() {
+                            break;
+                        }
+                    }
+                    Ok(status)
+                })
+            }
+            CompoundCommand::ArithmeticFor {
+                init,
+                condition,
+                step,
+                body,
+            } => self.run_loop(|handler| {
+                // an empty expression is skipped, and an empty condition is always true
+                let evaluate =
+                    |handler: &mut CommandHandler, expression: &str| match expression.trim() {
+                        "" => Ok(1),
+                        expression => expand::expand_arithmetic(handler, expression),
+                    };
+                let mut status = 0;
+                evaluate(handler, init)?;
+                while evaluate(handler, condition)? != 0 {
+                    status = handler.run_list(body, io_handler);
+                    if handler.end_iteration() {
+                        break;
+                    }
+                    evaluate(handler, step)?;
+                }
+                Ok(status)
+            }),
+            CompoundCommand::Case { word, items } => self.run_case(word, items, io_handler),
+        }
+    }
+
+    /// Runs the arms of a `case` whose patterns match the expanded `word`; patterns are
+    /// expanded without field splitting, and only when they are tested.
+    fn run_case(
+        &mut self,
+        word: &str,
+        items: &[CaseItem],
+        io_handler: &mut IOHandler,
+    ) -> ShellResult {
+        let word: Vec<char> = expand::expand_single(self, word)?.chars().collect();
+        let mut status = 0;
+        let mut fall_through = false;
+        for item in items {
+            if !fall_through {
+                let mut matched = false;
+                for pattern in &item.patterns {
+                    if expand::expand_pattern(self, pattern)?.matches(&word) {
+                        matched = true;
+                        break;
+                    }
+                }
+                if !matched {
+                    continue;
+                }
+            }
+            status = self.run_list(&item.body, io_handler);
+            if self.loop_control.is_some() {
+                break;
+            }
+            match item.terminator {
+                CaseTerminator::Break => break,
+                CaseTerminator::FallThrough => fall_through = true,
+                CaseTerminator::Continue => fall_through = false,
+            }
+        }
+        Ok(status)
+    }
+
+    /// Runs a loop, counting it as an enclosing loop for `break` and `continue`.
+    fn run_loop(&mut self, run: impl FnOnce(&mut CommandHandler) -> ShellResult) -> ShellResult {
+        self.loop_depth += 1;
+        let result = run(self);
+        self.loop_depth -= 1;
+        result
     }
 
-    fn run_external_command(&self, parsed_command: ParsedCommand, io_handler: &IOHandler) -> ShellResult {
-        match search_file_in_paths(&parsed_command.command, self.get_runtime_path()) {
-            Some(_) => execute_external(&parsed_command.command, parsed_command.args, io_handler),
-            None => return Err(ShellError::CommandNotFound(parsed_command.command)),
+    /// Takes a pending `break` or `continue` at the end of a loop iteration, returning
+    /// whether this loop has to stop; one aimed at an outer loop is passed on.
+    fn end_iteration(&mut self) -> bool {
+        match self.loop_control.take() {
+            None | Some(LoopControl::Continue(1)) => false,
+            Some(LoopControl::Break(1)) => true,
+            Some(LoopControl::Break(n)) => {
+                self.loop_control = Some(LoopControl::Break(n - 1));
+                true
+            }
+            Some(LoopControl::Continue(n)) => {
+                self.loop_control = Some(LoopControl::Continue(n - 1));
+                true
+            }
+            Some(control @ LoopControl::Return(_)) => {
+                self.loop_control = Some(control);
+                true
+            }
         }
     }
 
-    pub fn run(&mut self, parsed_command: ParsedCommand, io_handler: &mut IOHandler) -> ShellResult {
-        let result: ShellResult = match parsed_command.command.parse::<BuiltIn>() {
-            Ok(cmd) => self.run_built_in_command(cmd, parsed_command, io_handler),
-            _ => self.run_external_command(parsed_command, io_handler),
+    /// Runs `script` for `$(...)` in a forked copy of the shell and returns what it wrote to
+    /// stdout without trailing newlines. Nothing the script changes, not even an `exit`,
+    /// reaches the shell.
+    pub fn substitute(&mut self, script: &str) -> Result<String, ShellError> {
+        let command_list = match parse(script) {
+            Ok(command_list) => command_list,
+            Err(ShellError::NullInput) => CommandList::new(),
+            Err(e) => return Err(e),
+        };
+        let mut io_handler = IOHandler::new();
+        io_handler.set_stdout_buffer();
+        let status = run_forked(&io_handler, |child_io| {
+            self.run_list(&command_list, child_io)
+        })?;
+        self.variables.last_status = status;
+        self.substitution_status = Some(status);
+        let output = io_handler.take_output();
+        Ok(output.trim_end_matches('\n').to_string())
+    }
+
+    /// Collects finished background jobs so they do not linger as zombies.
+    pub fn reap_jobs(&mut self) {
+        self.jobs.retain(|&pid| is_running(pid));
+    }
+
+    /// Runs the stages of a pipeline concurrently, each in a forked copy of the shell, so that
+    /// none of them can block the others or change the shell itself.
+    pub fn run_pipeline(
+        &mut self,
+        pipeline: &[ShellCommand],
+        io_handler: &mut IOHandler,
+        background: bool,
+    ) -> ShellResult {
+        let stage_count = pipeline.len();
+        let mut statuses: Vec<i32> = vec![0; stage_count];
+        let mut children: Vec<(usize, Process)> = Vec::new();
+        // the read end of the pipe the previous stage writes into
+        let mut next_stdin: Option<File> = None;
+        for (i, command) in pipeline.iter().enumerate() {
+            let mut stage_io = io_handler.try_clone()?;
+            if let Some(reader) = next_stdin.take() {
+                stage_io.set_stdin_pipe(reader);
+            }
+            if i + 1 < stage_count {
+                let (reader, writer) = io::pipe()?;
+                stage_io.set_stdout_pipe(File::from(OwnedFd::from(writer)));
+                next_stdin = Some(File::from(OwnedFd::from(reader)));
+            }
+            match self.fork_stage(command, &stage_io) {
+                Ok(child) => children.push((i, child)),
+                Err(e) => {
+                    stage_io.stderr(format_args!("{}", e));
+                    statuses[i] = e.exit_code();
+                }
+            }
+            // dropping the stage closes our copies of its pipe ends
+        }
+        self.temp_path.clear();
+        if background {
+            // the last stage stands for the job
+            if let Some((_, child)) = children.last() {
+                self.variables.last_bg_pid = Some(child.pid as u32);
+                io_handler.stderr(format_args!("[{}] {}", self.jobs.len() + 1, child.pid));
+            }
+            self.jobs
+                .extend(children.into_iter().map(|(_, child)| child.pid));
+            return Ok(0);
+        }
+        for (i, child) in children {
+            statuses[i] = match child.wait(io_handler) {
+                Ok(status) => exit_code(status),
+                Err(e) => {
+                    let e = ShellError::Io(e);
+                    io_handler.stderr(format_args!("{}", e));
+                    e.exit_code()
+                }
+            };
+        }
+        Ok(statuses.last().copied().unwrap_or(0))
+    }
+
+    /// Expands and runs a pipeline stage in a forked copy of the shell, so that not even the
+    /// side effects of its expansions, such as `${x:=5}`, reach the shell.
+    fn fork_stage(
+        &mut self,
+        command: &ShellCommand,
+        stage_io: &IOHandler,
+    ) -> Result<Process, ShellError> {
+        Ok(spawn_forked(stage_io, |child_io| {
+            self.exec_command(command, child_io).unwrap_or_else(|e| {
+                child_io.stderr(format_args!("{}", e));
+                e.exit_code()
+            })
+        })?)
+    }
+
+    pub fn run(
+        &mut self,
+        parsed_command: ParsedCommand,
+        io_handler: &mut IOHandler,
+    ) -> ShellResult {
+        if parsed_command.command.is_empty() {
+            for (name, value) in &parsed_command.assignments {
+                self.set_var(name, value);
+            }
+            // a bare assignment reports the status of its last command substitution
+            return Ok(self.substitution_status.unwrap_or(0));
+        }
+        // functions are looked up first, so they can wrap builtins and programs alike
+        let result: ShellResult = match self.functions.get(&parsed_command.command).cloned() {
+            Some(function) => self.call_function(function, parsed_command, io_handler),
+            None => match parsed_command.command.parse::<BuiltIn>() {
+                Ok(cmd) => self.run_built_in_command(cmd, parsed_command, io_handler),
+                _ => self.run_external_command(parsed_command, io_handler),
+            },
         };
         self.temp_path.clear();
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Diagnostic;
+
+    fn simple(command: &ShellCommand) -> &ParsedCommand {
+        match command {
+            ShellCommand::Simple(parsed_command) => parsed_command,
+            command => panic!("not a simple command: {:?}", command),
+        }
+    }
+
+    fn syntax_error(input: &str) -> Diagnostic {
+        match parse(input) {
+            Err(ShellError::Syntax(diagnostic)) => diagnostic,
+            result => panic!("no syntax error in {:?}: {:?}", input, result),
+        }
+    }
+
+    #[test]
+    fn parses_lists_and_pipelines() {
+        let list = parse("! a | b && c || d; e &").unwrap();
+        let connectors: Vec<Connector> = list.iter().map(|item| item.connector).collect();
+        assert_eq!(
+            connectors,
+            [
+                Connector::Seq,
+                Connector::And,
+                Connector::Or,
+                Connector::Seq
+            ]
+        );
+        assert!(list[0].pipeline.negated);
+        assert_eq!(list[0].pipeline.commands.len(), 2);
+        assert_eq!(simple(&list[0].pipeline.commands[1]).command, "b");
+        assert!(!list[2].background);
+        assert!(list[3].background);
+    }
+
+    #[test]
+    fn separates_assignments_from_words() {
+        let list = parse("A=1 B=x cmd C=2 arg").unwrap();
+        let parsed_command = simple(&list[0].pipeline.commands[0]);
+        assert_eq!(
+            parsed_command.assignments,
+            [
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "x".to_string())
+            ]
+        );
+        assert_eq!(parsed_command.command, "cmd");
+        assert_eq!(parsed_command.args, ["C=2", "arg"]);
+    }
+
+    #[test]
+    fn keeps_redirections_in_source_order() {
+        let list = parse("cmd 2>&1 >f").unwrap();
+        let redirects = &simple(&list[0].pipeline.commands[0]).redirects;
+        assert!(matches!(
+            redirects[..],
+            [
+                Redirect::Duplicate { fd: 2, target: 1 },
+                Redirect::Output { fd: 1, .. },
+            ]
+        ));
+        let list = parse("cmd >f 2>&1").unwrap();
+        let redirects = &simple(&list[0].pipeline.commands[0]).redirects;
+        assert!(matches!(
+            redirects[..],
+            [
+                Redirect::Output { fd: 1, .. },
+                Redirect::Duplicate { fd: 2, target: 1 },
+            ]
+        ));
+    }
+
+    #[test]
+    fn accepts_a_command_of_only_redirections() {
+        let list = parse("> out").unwrap();
+        let parsed_command = simple(&list[0].pipeline.commands[0]);
+        assert!(parsed_command.command.is_empty());
+        assert_eq!(parsed_command.redirects.len(), 1);
+    }
+
+    #[test]
+    fn reads_here_document_bodies() {
+        let list = parse("cat <<-EOF; echo after\n\tline\n\tEOF\necho next").unwrap();
+        assert_eq!(list.len(), 3);
+        let redirects = &simple(&list[0].pipeline.commands[0]).redirects;
+        let [Redirect::HereDoc { heredoc, .. }] = &redirects[..] else {
+            panic!("no here-document: {:?}", redirects);
+        };
+        assert_eq!(heredoc.body, "line\n");
+        assert_eq!(simple(&list[2].pipeline.commands[0]).command, "echo");
+    }
+
+    #[test]
+    fn parses_compound_commands() {
+        let list = parse("if a; then b; elif c; then d; else e; fi > log").unwrap();
+        let ShellCommand::Compound(
+            CompoundCommand::If {
+                branches,
+                otherwise,
+            },
+            redirects,
+        ) = &list[0].pipeline.commands[0]
+        else {
+            panic!("not an if: {:?}", list);
+        };
+        assert_eq!(branches.len(), 2);
+        assert!(otherwise.is_some());
+        assert_eq!(redirects.len(), 1);
+
+        let list = parse("for i in a b; do x; done; for ((i = 0; i < 3; i++)) do y; done").unwrap();
+        assert!(matches!(
+            &list[0].pipeline.commands[0],
+            ShellCommand::Compound(CompoundCommand::For { words: Some(words), .. }, _)
+                if words.len() == 2
+        ));
+        assert!(matches!(
+            &list[1].pipeline.commands[0],
+            ShellCommand::Compound(CompoundCommand::ArithmeticFor { .. }, _)
+        ));
+    }
+
+    #[test]
+    fn points_at_the_unexpected_token() {
+        let diagnostic = syntax_error("echo a )");
+        assert_eq!(diagnostic.message, "unexpected token ')'");
+        assert_eq!(diagnostic.span, 7..8);
+        assert!(!diagnostic.incomplete);
+        assert_eq!(
+            diagnostic.to_string(),
+            "syntax error: unexpected token ')'\necho a )\n       ^"
+        );
+        assert_eq!(syntax_error("a && ;").span, 5..6);
+        assert_eq!(syntax_error("x\n| y").span, 2..3);
+    }
+
+    #[test]
+    fn marks_unfinished_input_as_incomplete() {
+        for input in [
+            "a |",
+            "a &&",
+            "if a; then b",
+            "while a",
+            "{ a",
+            "( a",
+            "cat <<EOF\nx",
+        ] {
+            assert!(syntax_error(input).incomplete, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn reads_a_script_one_line_at_a_time() {
+        let mut script = Script::new("\na; b &\nif c\nthen d; fi\n\ne )\nf\n");
+        let list = script.next_command().unwrap().unwrap();
+        assert_eq!(list.len(), 2);
+        assert!(list[1].background);
+        let list = script.next_command().unwrap().unwrap();
+        assert!(matches!(
+            list[0].pipeline.commands[0],
+            ShellCommand::Compound(CompoundCommand::If { .. }, _)
+        ));
+        let diagnostic = match script.next_command() {
+            Err(ShellError::Syntax(diagnostic)) => diagnostic,
+            result => panic!("no syntax error: {:?}", result),
+        };
+        assert_eq!(diagnostic.span, 27..28);
+
+        let mut script = Script::new("a\n\n");
+        assert!(script.next_command().unwrap().is_some());
+        assert!(script.next_command().unwrap().is_none());
+    }
+
+    fn case_items(input: &str) -> Vec<CaseItem> {
+        let list = parse(input).unwrap();
+        match &list[0].pipeline.commands[0] {
+            ShellCommand::Compound(CompoundCommand::Case { items, .. }, _) => items.clone(),
+            command => panic!("not a case: {:?}", command),
+        }
+    }
+
+    #[test]
+    fn parses_case_terminators() {
+        let items = case_items("case $x in a|b) one;; (c) two;& d) three;;& *) esac");
+        let terminators: Vec<CaseTerminator> = items.iter().map(|item| item.terminator).collect();
+        assert_eq!(
+            terminators,
+            [
+                CaseTerminator::Break,
+                CaseTerminator::FallThrough,
+                CaseTerminator::Continue,
+                CaseTerminator::Break,
+            ]
+        );
+        assert_eq!(items[0].patterns, ["a", "b"]);
+        assert_eq!(items[1].patterns, ["c"]);
+        // an arm may have an empty body
+        assert!(items[3].body.is_empty());
+    }
+
+    #[test]
+    fn parses_case_over_several_lines() {
+        let items = case_items("case $1 in\n  *.rs)\n    rustc\n    ;;\n\n  *) other\nesac");
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].body.len(), 1);
+        assert_eq!(syntax_error("case x in a b) c;; esac").span, 12..13);
+        assert!(syntax_error("case x in a) b").incomplete);
+    }
+
+    #[test]
+    fn pipes_a_large_here_document_into_a_group() {
+        // bigger than a pipe's buffer, so the writer is still busy when the group starts
+        let lines: String = (0..20000).map(|i| format!("line {}\n", i)).collect();
+        let script = format!("cat <<EOF | {{ wc -l; }}\n{}EOF", lines);
+        let output = CommandHandler::new().substitute(&script).unwrap();
+        assert_eq!(output.trim(), "20000");
+    }
+
+    fn run(script: &str) -> String {
+        CommandHandler::new().substitute(script).unwrap()
+    }
+
+    #[test]
+    fn scopes_local_variables_to_functions() {
+        let script = "f() { local x=inner; g; echo \"f:$x\"; }\n\
+                      g() { echo \"g:$x\"; x=changed; }\n\
+                      f; echo \"top:${x-unset}\"";
+        assert_eq!(run(script), "g:inner\nf:changed\ntop:unset");
+    }
+
+    #[test]
+    fn restores_shadowed_globals_on_return() {
+        let script = "x=global; export x\n\
+                      f() { local x; echo \"f:${x-unset}\"; x=local; }\n\
+                      f; echo \"top:$x\"; sh -c 'echo \"env:$x\"'";
+        assert_eq!(run(script), "f:unset\ntop:global\nenv:global");
+    }
+
+    #[test]
+    fn rejects_local_outside_functions() {
+        let script = "local x=1 2>/dev/null; echo \"$? ${x-unset}\"";
+        assert_eq!(run(script), "1 unset");
+    }
+}
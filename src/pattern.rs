@@ -0,0 +1,396 @@
+/// One element of a shell pattern.
+#[derive(Debug, Clone)]
+enum Token {
+    Literal(char),
+    /// `?`
+    Any,
+    /// `*`
+    Star,
+    /// `[...]`, or `[!...]` when negated
+    Class {
+        negated: bool,
+        items: Vec<ClassItem>,
+    },
+    /// The extglob forms `?(...)`, `*(...)`, `+(...)`, `@(...)` and `!(...)`, with `kind`
+    /// the character before the parenthesis.
+    Group {
+        kind: char,
+        alternatives: Vec<Vec<Token>>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum ClassItem {
+    Char(char),
+    Range(char, char),
+    /// `[:alpha:]` and friends
+    Named(String),
+}
+
+impl ClassItem {
+    fn matches(&self, ch: char) -> bool {
+        match self {
+            ClassItem::Char(c) => *c == ch,
+            ClassItem::Range(low, high) => (*low..=*high).contains(&ch),
+            ClassItem::Named(name) => match name.as_str() {
+                "alnum" => ch.is_alphanumeric(),
+                "alpha" => ch.is_alphabetic(),
+                "blank" => ch == ' ' || ch == '\t',
+                "cntrl" => ch.is_control(),
+                "digit" => ch.is_ascii_digit(),
+                "graph" => ch.is_ascii_graphic(),
+                "lower" => ch.is_lowercase(),
+                "print" => ch.is_ascii_graphic() || ch == ' ',
+                "punct" => ch.is_ascii_punctuation(),
+                "space" => ch.is_whitespace(),
+                "upper" => ch.is_uppercase(),
+                "xdigit" => ch.is_ascii_hexdigit(),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// A compiled shell pattern (`*`, `?`, bracket expressions and, with extglob, pattern
+/// lists). Quoted characters always match themselves.
+#[derive(Debug, Clone)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+    nocase: bool,
+}
+
+impl Pattern {
+    /// Compiles a pattern from characters paired with whether they were quoted.
+    pub fn new(pattern: &[(char, bool)], extglob: bool) -> Pattern {
+        Pattern {
+            tokens: Self::compile(pattern, extglob),
+            nocase: false,
+        }
+    }
+
+    /// Makes letters match regardless of case, as for `nocaseglob`.
+    pub fn ignore_case(mut self) -> Pattern {
+        self.nocase = true;
+        self
+    }
+
+    fn compile(pattern: &[(char, bool)], extglob: bool) -> Vec<Token> {
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < pattern.len() {
+            let (ch, quoted) = pattern[i];
+            i += 1;
+            if quoted {
+                tokens.push(Token::Literal(ch));
+                continue;
+            }
+            if extglob
+                && matches!(ch, '?' | '*' | '+' | '@' | '!')
+                && let Some(('(', false)) = pattern.get(i)
+                && let Some((alternatives, next)) = Self::group(pattern, i + 1, extglob)
+            {
+                tokens.push(Token::Group {
+                    kind: ch,
+                    alternatives,
+                });
+                i = next;
+                continue;
+            }
+            match ch {
+                '*' => {
+                    if !matches!(tokens.last(), Some(Token::Star)) {
+                        tokens.push(Token::Star);
+                    }
+                }
+                '?' => tokens.push(Token::Any),
+                '\\' if i < pattern.len() => {
+                    tokens.push(Token::Literal(pattern[i].0));
+                    i += 1;
+                }
+                '[' => match Self::class(pattern, i) {
+                    Some((token, next)) => {
+                        tokens.push(token);
+                        i = next;
+                    }
+                    None => tokens.push(Token::Literal('[')),
+                },
+                _ => tokens.push(Token::Literal(ch)),
+            }
+        }
+        tokens
+    }
+
+    /// Parses the `|`-separated alternatives of a pattern list starting just after `(`;
+    /// `None` if it is never closed.
+    fn group(
+        pattern: &[(char, bool)],
+        start: usize,
+        extglob: bool,
+    ) -> Option<(Vec<Vec<Token>>, usize)> {
+        let mut alternatives = Vec::new();
+        let mut depth = 0;
+        let mut alternative_start = start;
+        for (i, &(ch, quoted)) in pattern.iter().enumerate().skip(start) {
+            match (ch, quoted) {
+                ('(', false) => depth += 1,
+                (')', false) if depth > 0 => depth -= 1,
+                ('|' | ')', false) if depth == 0 => {
+                    let alternative = &pattern[alternative_start..i];
+                    alternatives.push(Self::compile(alternative, extglob));
+                    alternative_start = i + 1;
+                    if ch == ')' {
+                        return Some((alternatives, i + 1));
+                    }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Parses a bracket expression starting just after `[`; `None` if it is never closed.
+    fn class(pattern: &[(char, bool)], start: usize) -> Option<(Token, usize)> {
+        let mut i = start;
+        let negated = matches!(pattern.get(i), Some(('!' | '^', false)));
+        if negated {
+            i += 1;
+        }
+        let mut items = Vec::new();
+        let first = i;
+        while i < pattern.len() {
+            let (ch, quoted) = pattern[i];
+            if ch == ']' && !quoted && i > first {
+                return Some((Token::Class { negated, items }, i + 1));
+            }
+            if ch == '[' && !quoted && matches!(pattern.get(i + 1), Some((':', false))) {
+                let rest: String = pattern[i + 2..].iter().map(|(ch, _)| ch).collect();
+                if let Some(end) = rest.find(":]") {
+                    items.push(ClassItem::Named(rest[..end].to_string()));
+                    i += 2 + rest[..end].chars().count() + 2;
+                    continue;
+                }
+            }
+            let ch = match (ch, quoted) {
+                ('\\', false) if i + 1 < pattern.len() => {
+                    i += 1;
+                    pattern[i].0
+                }
+                _ => ch,
+            };
+            match (pattern.get(i + 1), pattern.get(i + 2)) {
+                (Some(('-', false)), Some(&(high, _))) if high != ']' => {
+                    items.push(ClassItem::Range(ch, high));
+                    i += 3;
+                }
+                _ => {
+                    items.push(ClassItem::Char(ch));
+                    i += 1;
+                }
+            }
+        }
+        None
+    }
+
+    /// Whether the pattern contains any unquoted special characters.
+    pub fn has_magic(&self) -> bool {
+        self.tokens
+            .iter()
+            .any(|token| !matches!(token, Token::Literal(_)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Whether the pattern matches all of `text`.
+    pub fn matches(&self, text: &[char]) -> bool {
+        let mut starts = vec![false; text.len() + 1];
+        starts[0] = true;
+        self.run(&self.tokens, text, starts)[text.len()]
+    }
+
+    /// Matches `tokens` from every position set in `starts` and returns the positions
+    /// where a match can end.
+    fn run(&self, tokens: &[Token], text: &[char], starts: Vec<bool>) -> Vec<bool> {
+        let mut matched = starts;
+        for token in tokens {
+            if !matched.contains(&true) {
+                break;
+            }
+            let mut next = vec![false; text.len() + 1];
+            match token {
+                Token::Star => {
+                    let mut any = false;
+                    for j in 0..=text.len() {
+                        any |= matched[j];
+                        next[j] = any;
+                    }
+                }
+                Token::Group { kind, alternatives } => {
+                    next = self.run_group(*kind, alternatives, text, matched);
+                }
+                _ => {
+                    for j in 0..text.len() {
+                        if matched[j] && self.matches_char(token, text[j]) {
+                            next[j + 1] = true;
+                        }
+                    }
+                }
+            }
+            matched = next;
+        }
+        matched
+    }
+
+    fn run_group(
+        &self,
+        kind: char,
+        alternatives: &[Vec<Token>],
+        text: &[char],
+        starts: Vec<bool>,
+    ) -> Vec<bool> {
+        let once = |starts: &Vec<bool>| {
+            let mut ends = vec![false; text.len() + 1];
+            for alternative in alternatives {
+                let alternative_ends = self.run(alternative, text, starts.clone());
+                for (end, matched) in ends.iter_mut().zip(alternative_ends) {
+                    *end |= matched;
+                }
+            }
+            ends
+        };
+        match kind {
+            '@' => once(&starts),
+            '?' => {
+                let mut ends = once(&starts);
+                for (end, start) in ends.iter_mut().zip(&starts) {
+                    *end |= start;
+                }
+                ends
+            }
+            '*' | '+' => {
+                // keep matching one more repetition from the newly reached positions only
+                let mut ends = if kind == '*' {
+                    starts.clone()
+                } else {
+                    vec![false; text.len() + 1]
+                };
+                let mut frontier = once(&starts);
+                while frontier.contains(&true) {
+                    let mut fresh = vec![false; text.len() + 1];
+                    for (j, reached) in frontier.into_iter().enumerate() {
+                        if reached && !ends[j] {
+                            ends[j] = true;
+                            fresh[j] = true;
+                        }
+                    }
+                    frontier = if fresh.contains(&true) {
+                        once(&fresh)
+                    } else {
+                        fresh
+                    };
+                }
+                ends
+            }
+            // `!(...)`: any span that none of the alternatives matches in full
+            _ => {
+                let mut ends = vec![false; text.len() + 1];
+                for i in (0..=text.len()).filter(|&i| starts[i]) {
+                    for (j, end) in ends.iter_mut().enumerate().skip(i) {
+                        let span = &text[i..j];
+                        let mut span_starts = vec![false; span.len() + 1];
+                        span_starts[0] = true;
+                        let excluded = alternatives.iter().any(|alternative| {
+                            self.run(alternative, span, span_starts.clone())[span.len()]
+                        });
+                        *end |= !excluded;
+                    }
+                }
+                ends
+            }
+        }
+    }
+
+    fn matches_char(&self, token: &Token, ch: char) -> bool {
+        match token {
+            Token::Literal(c) if self.nocase => c.eq_ignore_ascii_case(&ch),
+            Token::Literal(c) => *c == ch,
+            Token::Any => true,
+            Token::Class { negated, items } => {
+                let candidates = if self.nocase {
+                    vec![ch.to_ascii_lowercase(), ch.to_ascii_uppercase()]
+                } else {
+                    vec![ch]
+                };
+                let hit = candidates
+                    .into_iter()
+                    .any(|ch| items.iter().any(|item| item.matches(ch)));
+                hit != *negated
+            }
+            Token::Star | Token::Group { .. } => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        let pattern: Vec<(char, bool)> = pattern.chars().map(|ch| (ch, false)).collect();
+        let text: Vec<char> = text.chars().collect();
+        Pattern::new(&pattern, true).matches(&text)
+    }
+
+    #[test]
+    fn matches_wildcards_and_classes() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(!matches("*.rs", "main.rsx"));
+        assert!(matches("?.c", "a.c"));
+        assert!(!matches("?.c", "ab.c"));
+        assert!(matches("[a-c]x", "bx"));
+        assert!(!matches("[!a-c]x", "bx"));
+        assert!(matches("[[:digit:]]*", "1abc"));
+        assert!(matches("", ""));
+    }
+
+    #[test]
+    fn quoted_characters_match_themselves() {
+        let pattern = [('*', true), ('.', false), ('*', false)];
+        assert!(Pattern::new(&pattern, false).matches(&['*', '.', 'x']));
+        assert!(!Pattern::new(&pattern, false).matches(&['a', '.', 'x']));
+        assert!(Pattern::new(&pattern, false).has_magic());
+        assert!(!Pattern::new(&pattern[..2], false).has_magic());
+    }
+
+    #[test]
+    fn matches_extglob_groups() {
+        assert!(matches("?(a|b)c", "c"));
+        assert!(matches("?(a|b)c", "ac"));
+        assert!(!matches("?(a|b)c", "abc"));
+        assert!(matches("*(ab)", ""));
+        assert!(matches("*(ab)", "abab"));
+        assert!(!matches("+(ab)", ""));
+        assert!(matches("+(ab|c)", "abcab"));
+        assert!(matches("@(foo|bar).txt", "bar.txt"));
+        assert!(!matches("@(foo|bar).txt", "foobar.txt"));
+        assert!(matches("!(*.rs)", "main.c"));
+        assert!(!matches("!(*.rs)", "main.rs"));
+    }
+
+    #[test]
+    fn extglob_is_literal_when_disabled() {
+        let pattern: Vec<(char, bool)> = "@(a|b)".chars().map(|ch| (ch, false)).collect();
+        let pattern = Pattern::new(&pattern, false);
+        assert!(!pattern.matches(&['a']));
+        assert!(pattern.matches(&"@(a|b)".chars().collect::<Vec<_>>()));
+    }
+
+    #[test]
+    fn ignores_case_when_asked() {
+        let pattern: Vec<(char, bool)> = "*.RS".chars().map(|ch| (ch, false)).collect();
+        let text: Vec<char> = "main.rs".chars().collect();
+        assert!(!Pattern::new(&pattern, false).matches(&text));
+        assert!(Pattern::new(&pattern, false).ignore_case().matches(&text));
+    }
+}
@@ -1,12 +1,15 @@
+use std::cell::RefCell;
 use std::fmt;
 use std::fs::{File, OpenOptions};
 use std::io::{self, Read, Write};
-use std::process::Stdio;
+use std::os::fd::{AsFd, AsRawFd, RawFd};
+use std::rc::Rc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum IOMode {
     PIPED,
     FILE,
+    BUFFER,
     INHERIT,
     NULL,
 }
@@ -19,13 +22,26 @@ pub enum OutLevel {
     ERROR,
 }
 
+#[derive(Debug, Clone)]
+pub enum InMode {
+    READ,
+    READWRITE,
+}
+
 #[derive(Debug, Clone)]
 pub enum OutMode {
     WRITE,
     APPEND,
 }
 
-pub type PipeHandler = Option<Stdio>;
+pub type PipeHandler = Option<File>;
+
+type Stream<'a> = (
+    &'a mut IOMode,
+    &'a mut String,
+    &'a mut PipeHandler,
+    &'a mut PipeHandler,
+);
 
 #[derive(Debug)]
 pub struct IOHandler {
@@ -37,12 +53,23 @@ pub struct IOHandler {
     pub stdout_redirect_path: String,
     pub stderr_redirect_path: String,
 
+    pub stdin_buffer: String,
+    /// Output captured from streams in `BUFFER` mode, shared with every copy of the handler.
+    pub out_buffer: Rc<RefCell<String>>,
+
     pub stdin_pipe: PipeHandler,
     pub stdout_pipe: PipeHandler,
     pub stderr_pipe: PipeHandler,
 
-    pub stdout_file_mode: Option<OutMode>,
-    pub stderr_file_mode: Option<OutMode>,
+    pub stdin_file: PipeHandler,
+    pub stdout_file: PipeHandler,
+    pub stderr_file: PipeHandler,
+}
+
+impl Default for IOHandler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl IOHandler {
@@ -56,11 +83,14 @@ impl IOHandler {
             stdin_redirect_path: String::new(),
             stdout_redirect_path: String::new(),
             stderr_redirect_path: String::new(),
+            stdin_buffer: String::new(),
+            out_buffer: Rc::default(),
             stdin_pipe: None,
             stdout_pipe: None,
             stderr_pipe: None,
-            stdout_file_mode: None,
-            stderr_file_mode: None,
+            stdin_file: None,
+            stdout_file: None,
+            stderr_file: None,
         }
     }
 
@@ -68,19 +98,39 @@ impl IOHandler {
         print!("$ ");
     }
 
+    /// Reads a line of input without its newline; fails with `UnexpectedEof` once input is
+    /// exhausted.
     pub fn get_raw_command() -> io::Result<String> {
         let mut buffer = String::new();
         io::stdout().flush()?;
-        io::stdin().read_line(&mut buffer)?;
-        Ok(buffer.trim().to_string())
+        if io::stdin().read_line(&mut buffer)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(buffer.trim_end_matches(['\n', '\r']).to_string())
+    }
+
+    /// Prints `prompt` (`PS2`) and reads one more line of an incomplete command without its
+    /// newline; `None` once input is exhausted.
+    pub fn get_continuation_line(prompt: &str) -> io::Result<Option<String>> {
+        print!("{}", prompt);
+        let mut buffer = String::new();
+        io::stdout().flush()?;
+        if io::stdin().read_line(&mut buffer)? == 0 {
+            return Ok(None);
+        }
+        if buffer.ends_with('\n') {
+            buffer.pop();
+        }
+        Ok(Some(buffer))
     }
 
     pub fn stdin(&self) -> io::Result<String> {
         match self.stdin_mode {
             IOMode::FILE => {
-                let mut file = File::open(&self.stdin_redirect_path)?;
                 let mut buffer = String::new();
-                file.read_to_string(&mut buffer)?;
+                if let Some(mut file) = self.stdin_file.as_ref() {
+                    file.read_to_string(&mut buffer)?;
+                }
                 Ok(buffer)
             }
             IOMode::INHERIT => {
@@ -89,7 +139,14 @@ impl IOHandler {
                 io::stdin().read_line(&mut buffer)?;
                 Ok(buffer.trim().to_string())
             }
-            IOMode::PIPED => Ok(String::new()),
+            IOMode::PIPED => {
+                let mut buffer = String::new();
+                if let Some(mut pipe) = self.stdin_pipe.as_ref() {
+                    pipe.read_to_string(&mut buffer)?;
+                }
+                Ok(buffer)
+            }
+            IOMode::BUFFER => Ok(self.stdin_buffer.clone()),
             IOMode::NULL => Ok(String::new()),
         }
     }
@@ -97,16 +154,10 @@ impl IOHandler {
     pub fn stdout(&self, args: fmt::Arguments) {
         match self.stdout_mode {
             IOMode::FILE => {
-                let file_result = OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(&self.stdout_redirect_path);
-
-                if let Ok(mut file) = file_result {
+                if let Some(mut file) = self.stdout_file.as_ref() {
                     let _ = file.write_fmt(args);
                     let _ = writeln!(file);
-                } else if let Err(e) = file_result {
-                    println!("{}", e);
+                } else {
                     eprintln!(
                         "[ShellIO Error] Unable to write to file: {}",
                         self.stdout_redirect_path
@@ -117,7 +168,13 @@ impl IOHandler {
                 let _ = io::stdout().write_fmt(args);
                 println!();
             }
-            IOMode::PIPED => {}
+            IOMode::PIPED => {
+                if let Some(mut pipe) = self.stdout_pipe.as_ref() {
+                    let _ = pipe.write_fmt(args);
+                    let _ = writeln!(pipe);
+                }
+            }
+            IOMode::BUFFER => self.write_output(&format!("{}\n", args)),
             IOMode::NULL => {}
         }
     }
@@ -129,11 +186,7 @@ impl IOHandler {
         ));
         match self.stderr_mode {
             IOMode::FILE => {
-                let file_result = OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(&self.stderr_redirect_path);
-                if let Ok(mut file) = file_result {
+                if let Some(mut file) = self.stderr_file.as_ref() {
                     let _ = file.write_fmt(args);
                     let _ = writeln!(file);
                 } else {
@@ -147,59 +200,194 @@ impl IOHandler {
                 let _ = io::stderr().write_fmt(args);
                 eprintln!();
             }
-            IOMode::PIPED => {}
+            IOMode::PIPED => {
+                if let Some(mut pipe) = self.stderr_pipe.as_ref() {
+                    let _ = pipe.write_fmt(args);
+                    let _ = writeln!(pipe);
+                }
+            }
+            IOMode::BUFFER => self.write_output(&format!("{}\n", args)),
             IOMode::NULL => {}
         }
     }
 
-    pub fn set_stderr_file(&mut self, stderr_path: &mut String, mode: &OutMode) {
-        self.stderr_mode = IOMode::FILE;
-        self.stderr_redirect_path = std::mem::take(stderr_path);
+    pub fn write_output(&self, text: &str) {
+        self.out_buffer.borrow_mut().push_str(text);
+    }
 
-        let mut file: File;
+    pub fn take_output(&self) -> String {
+        std::mem::take(&mut *self.out_buffer.borrow_mut())
+    }
 
+    pub fn set_stdin_file(&mut self, stdin_path: &mut String, mode: &InMode) -> io::Result<()> {
+        let file = match mode {
+            InMode::READWRITE => OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&*stdin_path)?,
+            InMode::READ => File::open(&*stdin_path)?,
+        };
+        self.stdin_mode = IOMode::FILE;
+        self.stdin_redirect_path = std::mem::take(stdin_path);
+        self.stdin_file = Some(file);
+        Ok(())
+    }
+
+    fn open_out_file(path: &str, mode: &OutMode) -> io::Result<File> {
         match mode {
-            OutMode::APPEND => {
-                file = OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(self.stderr_redirect_path.clone())
-                    .unwrap();
-            }
-            OutMode::WRITE => {
-                file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(self.stderr_redirect_path.clone())
-                    .unwrap();
-            }
+            OutMode::APPEND => OpenOptions::new().create(true).append(true).open(path),
+            OutMode::WRITE => OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(path),
         }
-        let _ = file.write("".as_bytes());
     }
 
-    pub fn set_stdout_file(&mut self, stdout_path: &mut String, mode: &OutMode) {
+    pub fn set_stderr_file(&mut self, stderr_path: &mut String, mode: &OutMode) -> io::Result<()> {
+        let file = Self::open_out_file(stderr_path, mode)?;
+        self.stderr_mode = IOMode::FILE;
+        self.stderr_redirect_path = std::mem::take(stderr_path);
+        self.stderr_file = Some(file);
+        Ok(())
+    }
+
+    pub fn set_stdout_file(&mut self, stdout_path: &mut String, mode: &OutMode) -> io::Result<()> {
+        let file = Self::open_out_file(stdout_path, mode)?;
         self.stdout_mode = IOMode::FILE;
         self.stdout_redirect_path = std::mem::take(stdout_path);
+        self.stdout_file = Some(file);
+        Ok(())
+    }
 
-        let mut file: File;
+    pub fn set_stdin_buffer(&mut self, buffer: String) {
+        self.stdin_mode = IOMode::BUFFER;
+        self.stdin_buffer = buffer;
+    }
 
-        match mode {
-            OutMode::APPEND => {
-                file = OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(self.stdout_redirect_path.clone())
-                    .unwrap();
-            }
-            OutMode::WRITE => {
-                file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(self.stdout_redirect_path.clone())
-                    .unwrap();
-            }
+    /// Captures stdout into `out_buffer` instead of writing it anywhere.
+    pub fn set_stdout_buffer(&mut self) {
+        self.stdout_mode = IOMode::BUFFER;
+        self.out_buffer = Rc::default();
+    }
+
+    pub fn set_stdin_pipe(&mut self, pipe: File) {
+        self.stdin_mode = IOMode::PIPED;
+        self.stdin_pipe = Some(pipe);
+    }
+
+    pub fn set_stdout_pipe(&mut self, pipe: File) {
+        self.stdout_mode = IOMode::PIPED;
+        self.stdout_pipe = Some(pipe);
+    }
+
+    pub fn set_stderr_pipe(&mut self, pipe: File) {
+        self.stderr_mode = IOMode::PIPED;
+        self.stderr_pipe = Some(pipe);
+    }
+
+    fn stream_mut(&mut self, fd: i32) -> io::Result<Stream<'_>> {
+        match fd {
+            0 => Ok((
+                &mut self.stdin_mode,
+                &mut self.stdin_redirect_path,
+                &mut self.stdin_pipe,
+                &mut self.stdin_file,
+            )),
+            1 => Ok((
+                &mut self.stdout_mode,
+                &mut self.stdout_redirect_path,
+                &mut self.stdout_pipe,
+                &mut self.stdout_file,
+            )),
+            2 => Ok((
+                &mut self.stderr_mode,
+                &mut self.stderr_redirect_path,
+                &mut self.stderr_pipe,
+                &mut self.stderr_file,
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}: Bad file descriptor", fd),
+            )),
         }
-        let _ = file.write("".as_bytes());
+    }
+
+    /// Makes `fd` a copy of `target` (`fd>&target`); both then share the same open file
+    /// description, so writes through either advance the same offset.
+    pub fn duplicate(&mut self, fd: i32, target: i32) -> io::Result<()> {
+        self.stream_mut(fd)?;
+        let (mode, path, pipe, file) = self.stream_mut(target)?;
+        let mut mode = *mode;
+        let path = path.clone();
+        let pipe = pipe.as_ref().map(File::try_clone).transpose()?;
+        let mut file = file.as_ref().map(File::try_clone).transpose()?;
+        if matches!(mode, IOMode::INHERIT) && fd != target {
+            // the shell's own descriptor stands in for an inherited stream
+            let inherited = match target {
+                0 => io::stdin().as_fd().try_clone_to_owned()?,
+                1 => io::stdout().as_fd().try_clone_to_owned()?,
+                _ => io::stderr().as_fd().try_clone_to_owned()?,
+            };
+            mode = IOMode::FILE;
+            file = Some(File::from(inherited));
+        }
+
+        let (fd_mode, fd_path, fd_pipe, fd_file) = self.stream_mut(fd)?;
+        *fd_mode = mode;
+        *fd_path = path;
+        *fd_pipe = pipe;
+        *fd_file = file;
+        Ok(())
+    }
+
+    /// Closes `fd` (`fd>&-`); anything written to it afterwards is discarded.
+    pub fn close(&mut self, fd: i32) -> io::Result<()> {
+        let (mode, path, pipe, file) = self.stream_mut(fd)?;
+        *mode = IOMode::NULL;
+        path.clear();
+        *pipe = None;
+        *file = None;
+        Ok(())
+    }
+
+    /// The descriptors of the files and pipes the streams refer to.
+    pub fn raw_fds(&self) -> Vec<RawFd> {
+        [
+            &self.stdin_pipe,
+            &self.stdout_pipe,
+            &self.stderr_pipe,
+            &self.stdin_file,
+            &self.stdout_file,
+            &self.stderr_file,
+        ]
+        .into_iter()
+        .flatten()
+        .map(File::as_raw_fd)
+        .collect()
+    }
+
+    /// A copy of the handler whose streams refer to the same files, pipes and buffer.
+    pub fn try_clone(&self) -> io::Result<IOHandler> {
+        let clone = |handle: &PipeHandler| handle.as_ref().map(File::try_clone).transpose();
+        Ok(IOHandler {
+            stdin_mode: self.stdin_mode,
+            stdout_mode: self.stdout_mode,
+            stderr_mode: self.stderr_mode,
+            stdin_redirect_path: self.stdin_redirect_path.clone(),
+            stdout_redirect_path: self.stdout_redirect_path.clone(),
+            stderr_redirect_path: self.stderr_redirect_path.clone(),
+            stdin_buffer: self.stdin_buffer.clone(),
+            out_buffer: Rc::clone(&self.out_buffer),
+            stdin_pipe: clone(&self.stdin_pipe)?,
+            stdout_pipe: clone(&self.stdout_pipe)?,
+            stderr_pipe: clone(&self.stderr_pipe)?,
+            stdin_file: clone(&self.stdin_file)?,
+            stdout_file: clone(&self.stdout_file)?,
+            stderr_file: clone(&self.stderr_file)?,
+        })
     }
 
     pub fn reset(&mut self) {
@@ -208,12 +396,17 @@ impl IOHandler {
         self.stderr_mode = IOMode::INHERIT;
 
         self.stdin_redirect_path.clear();
+        self.stdin_buffer.clear();
         self.stdout_redirect_path.clear();
         self.stderr_redirect_path.clear();
 
         self.stdin_pipe = None;
         self.stdout_pipe = None;
         self.stderr_pipe = None;
+
+        self.stdin_file = None;
+        self.stdout_file = None;
+        self.stderr_file = None;
     }
 
     pub fn debug(args: fmt::Arguments) {
@@ -1,10 +1,18 @@
 use crate::error::ShellError;
 use crate::parse::{Args, RunTimeEnvPath, ShellResult};
-use crate::shellio::{IOHandler, IOMode};
-use std::fs::{self, OpenOptions};
+use crate::shellio::{IOHandler, IOMode, PipeHandler};
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::fd::{OwnedFd, RawFd};
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
-use std::process::{self, Stdio};
+use std::process::{self, ExitStatus, Stdio};
+use std::ptr;
+use std::thread::{self, JoinHandle};
 
 pub fn search_file_in_paths(filename: &String, paths: RunTimeEnvPath) -> Option<PathBuf> {
     paths.borrow().iter().find_map(|dir| {
@@ -17,6 +25,38 @@ pub fn search_file_in_paths(filename: &String, paths: RunTimeEnvPath) -> Option<
     })
 }
 
+/// Looks up the home directory of `user` in the passwd database, which includes users
+/// served through NSS such as LDAP.
+pub fn user_home(user: &str) -> Option<String> {
+    let name = CString::new(user).ok()?;
+    let mut buffer: Vec<libc::c_char> = vec![0; 1024];
+    loop {
+        // SAFETY: `passwd` is plain data that `getpwnam_r` fills in
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        // SAFETY: every pointer refers to a live local, and `buffer.len()` is its size
+        let error = unsafe {
+            libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            )
+        };
+        if error == libc::ERANGE {
+            buffer.resize(buffer.len() * 2, 0);
+            continue;
+        }
+        if error != 0 || result.is_null() {
+            return None;
+        }
+        // SAFETY: on success `pw_dir` points to a NUL-terminated string inside `buffer`
+        let home = unsafe { CStr::from_ptr(passwd.pw_dir) };
+        return Some(home.to_string_lossy().into_owned());
+    }
+}
+
 pub fn is_executable(file_path: &PathBuf) -> bool {
     match fs::metadata(file_path) {
         Ok(metadata) => {
@@ -27,40 +67,215 @@ pub fn is_executable(file_path: &PathBuf) -> bool {
     }
 }
 
-pub fn execute_external(program: &String, args: Args, io_handler: &IOHandler) -> ShellResult {
-    let out = match io_handler.stdout_mode {
-        IOMode::INHERIT => Stdio::inherit(),
-        IOMode::FILE => {
-            // let file_handle = File::create(io_handler.stdout_redirect_path.clone())?;
-            let file_handle = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(io_handler.stdout_redirect_path.clone())?;
-            Stdio::from(file_handle)
-        }
-        _ => Stdio::null(),
+/// Hands `buffer` to a child through a pipe, written from a helper thread so a body
+/// larger than the pipe capacity cannot block the shell.
+fn feed_stdin(buffer: String) -> io::Result<Stdio> {
+    let (reader, mut writer) = io::pipe()?;
+    thread::spawn(move || {
+        let _ = writer.write_all(buffer.as_bytes());
+    });
+    Ok(Stdio::from(reader))
+}
+
+fn to_stdio(mode: IOMode, pipe: &PipeHandler, file: &PipeHandler) -> io::Result<Stdio> {
+    let handle = match mode {
+        IOMode::INHERIT => return Ok(Stdio::inherit()),
+        IOMode::PIPED => pipe,
+        IOMode::FILE => file,
+        IOMode::BUFFER | IOMode::NULL => return Ok(Stdio::null()),
     };
-    let err = match io_handler.stderr_mode {
-        IOMode::INHERIT => Stdio::inherit(),
-        IOMode::FILE => {
-            // let file_handle = File::create(io_handler.stderr_redirect_path.clone())?;
-            let file_handle = OpenOptions::new()
-                .create(true)
-                .append(true)
-                .open(io_handler.stderr_redirect_path.clone())?;
+    match handle {
+        Some(handle) => Ok(Stdio::from(handle.try_clone()?)),
+        None => Ok(Stdio::null()),
+    }
+}
 
-            Stdio::from(file_handle)
+/// A spawned external command or forked copy of the shell, with the helper thread
+/// collecting anything it writes to a `BUFFER` stream.
+pub struct Process {
+    pub pid: libc::pid_t,
+    capture: Option<JoinHandle<String>>,
+}
+
+impl Process {
+    /// Waits for the process, then appends its captured output to `io_handler`.
+    pub fn wait(self, io_handler: &IOHandler) -> io::Result<ExitStatus> {
+        let mut status = 0;
+        // SAFETY: `pid` is our own child and `status` outlives the call
+        let result = unsafe { libc::waitpid(self.pid, &mut status, 0) };
+        if let Some(capture) = self.capture {
+            io_handler.write_output(&capture.join().unwrap_or_default());
+        }
+        if result == -1 {
+            return Err(io::Error::last_os_error());
         }
-        _ => Stdio::null(),
+        Ok(ExitStatus::from_raw(status))
+    }
+}
+
+/// Whether the background process `pid` is still running; one that has finished is
+/// collected so it does not linger as a zombie.
+pub fn is_running(pid: libc::pid_t) -> bool {
+    let mut status = 0;
+    // SAFETY: `status` outlives the call, and `WNOHANG` keeps it from blocking
+    unsafe { libc::waitpid(pid, &mut status, libc::WNOHANG) == 0 }
+}
+
+/// Reads everything from `reader` on a helper thread, for a child writing to a `BUFFER`
+/// stream.
+fn collect_output(mut reader: io::PipeReader) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let _ = reader.read_to_end(&mut output);
+        String::from_utf8_lossy(&output).into_owned()
+    })
+}
+
+pub fn spawn_external(
+    program: &String,
+    args: Args,
+    env: &BTreeMap<String, String>,
+    io_handler: &IOHandler,
+) -> Result<Process, ShellError> {
+    let input = match io_handler.stdin_mode {
+        IOMode::BUFFER => feed_stdin(io_handler.stdin_buffer.clone())?,
+        mode => to_stdio(mode, &io_handler.stdin_pipe, &io_handler.stdin_file)?,
     };
-    match process::Command::new(program)
+    // stdout and stderr captured together (`2>&1` inside `$(...)`) share a single pipe
+    let capture = match (io_handler.stdout_mode, io_handler.stderr_mode) {
+        (IOMode::BUFFER, _) | (_, IOMode::BUFFER) => Some(io::pipe()?),
+        _ => None,
+    };
+    let output = |mode: IOMode, pipe: &PipeHandler, file: &PipeHandler| match (mode, &capture) {
+        (IOMode::BUFFER, Some((_, writer))) => Ok(Stdio::from(writer.try_clone()?)),
+        _ => to_stdio(mode, pipe, file),
+    };
+    let out = output(
+        io_handler.stdout_mode,
+        &io_handler.stdout_pipe,
+        &io_handler.stdout_file,
+    )?;
+    let err = output(
+        io_handler.stderr_mode,
+        &io_handler.stderr_pipe,
+        &io_handler.stderr_file,
+    )?;
+    let child = process::Command::new(program)
         .args(args)
+        .env_clear()
+        .envs(env)
+        .stdin(input)
         .stdout(out)
         .stderr(err)
-        .spawn()?
-        .wait()
-    {
-        Ok(_) => Ok(1),
+        .spawn()
+        .map_err(|source| ShellError::ProcessStartError {
+            cmd: program.to_string(),
+            source,
+        })?;
+    // our copy of the write end must be gone before the reader can see end of file
+    let capture = capture.map(|(reader, writer)| {
+        drop(writer);
+        collect_output(reader)
+    });
+    // the process is waited for by pid, so the handle is not needed
+    Ok(Process {
+        pid: child.id() as libc::pid_t,
+        capture,
+    })
+}
+
+/// Runs `body` in a forked copy of the shell and returns the child's exit code, so that
+/// nothing `body` changes reaches the parent.
+pub fn run_forked(io_handler: &IOHandler, body: impl FnOnce(&mut IOHandler) -> i32) -> ShellResult {
+    match spawn_forked(io_handler, body)?.wait(io_handler) {
+        Ok(status) => Ok(exit_code(status)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Starts `body` in a forked copy of the shell without waiting for it. Output to a `BUFFER`
+/// stream is sent back through a pipe, as for external commands.
+pub fn spawn_forked(
+    io_handler: &IOHandler,
+    body: impl FnOnce(&mut IOHandler) -> i32,
+) -> io::Result<Process> {
+    let capture = match (io_handler.stdout_mode, io_handler.stderr_mode) {
+        (IOMode::BUFFER, _) | (_, IOMode::BUFFER) => Some(io::pipe()?),
+        _ => None,
+    };
+    // anything still buffered would otherwise be written by both processes
+    io::stdout().flush()?;
+    io::stderr().flush()?;
+    // SAFETY: the child only runs shell code and then exits without returning here
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            // like an external command, die when writing to a pipe whose reader is gone,
+            // rather than keep going with the write errors ignored
+            // SAFETY: resetting a signal to its default action has no preconditions
+            unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL) };
+            // errors must not escape here, or the child would carry on as the parent
+            let status = (|| -> io::Result<i32> {
+                let mut child_io = io_handler.try_clone()?;
+                if let Some((reader, writer)) = capture {
+                    drop(reader);
+                    if let IOMode::BUFFER = child_io.stdout_mode {
+                        child_io.set_stdout_pipe(File::from(OwnedFd::from(writer.try_clone()?)));
+                    }
+                    if let IOMode::BUFFER = child_io.stderr_mode {
+                        child_io.set_stderr_pipe(File::from(OwnedFd::from(writer)));
+                    }
+                }
+                close_other_fds(&child_io.raw_fds())?;
+                Ok(body(&mut child_io))
+            })()
+            .unwrap_or(1);
+            let _ = io::stdout().flush();
+            let _ = io::stderr().flush();
+            process::exit(status);
+        }
+        pid => {
+            let capture = capture.map(|(reader, writer)| {
+                drop(writer);
+                collect_output(reader)
+            });
+            Ok(Process { pid, capture })
+        }
+    }
+}
+
+/// Closes every descriptor above stderr except `keep`. A forked child would otherwise hold
+/// on to pipe ends that belong to the parent, such as the one a thread feeding a here-document
+/// writes to, and their readers would never see end of file.
+fn close_other_fds(keep: &[RawFd]) -> io::Result<()> {
+    let fds: Vec<RawFd> = fs::read_dir("/proc/self/fd")?
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+        .collect();
+    for fd in fds {
+        if fd > 2 && !keep.contains(&fd) {
+            // SAFETY: nothing in the child uses descriptors outside `keep` any more
+            unsafe { libc::close(fd) };
+        }
+    }
+    Ok(())
+}
+
+/// Converts a child's status into a POSIX exit code, `128 + n` when killed by signal `n`.
+pub fn exit_code(status: ExitStatus) -> i32 {
+    match status.code() {
+        Some(code) => code,
+        None => 128 + status.signal().unwrap_or(0),
+    }
+}
+
+pub fn execute_external(
+    program: &String,
+    args: Args,
+    env: &BTreeMap<String, String>,
+    io_handler: &IOHandler,
+) -> ShellResult {
+    match spawn_external(program, args, env, io_handler)?.wait(io_handler) {
+        Ok(status) => Ok(exit_code(status)),
         _ => Err(ShellError::ExecuteError(program.to_string())),
     }
 }
@@ -0,0 +1,139 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    os::unix::process::parent_id,
+    process,
+};
+
+/// Shell parameters: named variables plus the special and positional parameters.
+#[derive(Debug, Clone)]
+pub struct Variables {
+    vars: HashMap<String, String>,
+    exported: HashSet<String>,
+    pub arg0: String,
+    pub positional: Vec<String>,
+    pub last_status: i32,
+    pub last_bg_pid: Option<u32>,
+    /// The shell's own pid for `$$`, which subshells share.
+    shell_pid: u32,
+    // for each running function, innermost last, the value and export flag its `local`
+    // variables had before
+    scopes: Vec<HashMap<String, (Option<String>, bool)>>,
+}
+
+impl Default for Variables {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Variables {
+    pub fn new() -> Variables {
+        let mut args = env::args();
+        let mut variables = Variables {
+            vars: HashMap::new(),
+            exported: HashSet::new(),
+            arg0: args.next().unwrap_or_default(),
+            positional: args.collect(),
+            last_status: 0,
+            last_bg_pid: None,
+            shell_pid: process::id(),
+            scopes: Vec::new(),
+        };
+        for (name, value) in env::vars() {
+            variables.set(&name, &value);
+            variables.export(&name);
+        }
+        variables.set("PPID", &parent_id().to_string());
+        variables
+    }
+
+    pub fn is_name(name: &str) -> bool {
+        name.starts_with(|ch: char| ch == '_' || ch.is_ascii_alphabetic())
+            && name
+                .chars()
+                .all(|ch| ch == '_' || ch.is_ascii_alphanumeric())
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.vars.insert(name.to_string(), value.to_string());
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        self.vars.remove(name);
+        self.exported.remove(name);
+    }
+
+    pub fn export(&mut self, name: &str) {
+        self.exported.insert(name.to_string());
+    }
+
+    /// Whether a function is running.
+    pub fn in_function(&self) -> bool {
+        !self.scopes.is_empty()
+    }
+
+    /// Starts the scope of a function call.
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    /// Makes `name` local to the running function, so that it is restored when the function
+    /// returns; returns false outside a function.
+    pub fn make_local(&mut self, name: &str) -> bool {
+        let Some(scope) = self.scopes.last_mut() else {
+            return false;
+        };
+        if !scope.contains_key(name) {
+            let saved = (self.vars.get(name).cloned(), self.exported.contains(name));
+            scope.insert(name.to_string(), saved);
+        }
+        true
+    }
+
+    /// Ends the innermost function scope, restoring its local variables, and returns their
+    /// names.
+    pub fn pop_scope(&mut self) -> Vec<String> {
+        let scope = self.scopes.pop().unwrap_or_default();
+        for (name, (value, exported)) in &scope {
+            match value {
+                Some(value) => self.vars.insert(name.clone(), value.clone()),
+                None => self.vars.remove(name),
+            };
+            if *exported {
+                self.exported.insert(name.clone());
+            } else {
+                self.exported.remove(name);
+            }
+        }
+        scope.into_keys().collect()
+    }
+
+    /// The exported variables that have a value, sorted by name, as passed to children.
+    pub fn environment(&self) -> BTreeMap<String, String> {
+        self.exported
+            .iter()
+            .filter_map(|name| Some((name.clone(), self.vars.get(name)?.clone())))
+            .collect()
+    }
+
+    /// Looks up `name`, which may be a variable, a positional parameter or one of the
+    /// special parameters `? $ ! # 0 @ *`.
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_status.to_string()),
+            "$" => Some(self.shell_pid.to_string()),
+            "!" => self.last_bg_pid.map(|pid| pid.to_string()),
+            "#" => Some(self.positional.len().to_string()),
+            "0" => Some(self.arg0.clone()),
+            "@" | "*" => Some(self.positional.join(" ")),
+            _ => match name.parse::<usize>() {
+                Ok(n) => n
+                    .checked_sub(1)
+                    .and_then(|i| self.positional.get(i))
+                    .cloned(),
+                Err(_) => self.vars.get(name).cloned(),
+            },
+        }
+    }
+}